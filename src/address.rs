@@ -0,0 +1,350 @@
+use crate::input::{Complaint, Failure};
+use crate::search::{self, Needle};
+use crate::session::Session;
+use ec::State;
+
+
+/// Parses the up to two comma-separated addresses at the start of `input`,
+/// e.g. `$-f,$` or `/deadbeef/+4`.  Returns them with the rest of `input`.
+///
+/// An address is `.`, `$`, a number in the current radix (or explicitly hex
+/// or decimal with a `0x` or `0d` prefix), `/deadbeef/` for the next place
+/// those bytes occur, `?deadbeef?` for the previous place or `'a` for the byte
+/// marked `a`.  Searches can be for anything `Needle::from_pattern` takes.
+/// Any number of `+` or `-` offsets can follow.  A lone `+` or `-` is an
+/// offset of 1 and an address starting with an offset is relative to `.`.
+/// A range can't end before it begins.
+pub fn parse_addresses<'a>(state:&mut State, session:&mut Session,
+        input:&'a str) -> Result<(Vec<usize>, &'a str), Complaint> {
+    let mut addresses = vec![];
+
+    let (begin, the_rest) = match address(state, session, input)? {
+        Some(parsed) => parsed,
+        None => {
+            return Ok((addresses, input));
+        },
+    };
+    addresses.push(begin);
+
+    let after_comma = the_rest.trim_start();
+    if !after_comma.starts_with(',') {
+        return Ok((addresses, the_rest));
+    }
+
+    match address(state, session, &after_comma[1..])? {
+        Some((end, the_rest)) => {
+            if end < begin {
+                let radix = state.prefs.radix;
+                return Err(Complaint::new(Failure::BadRange,
+                        &format!("Range ends at {}, before it begins at {}",
+                        ec::hex_unless_dec_with_radix(end, radix),
+                        ec::hex_unless_dec_with_radix(begin, radix))));
+            }
+            addresses.push(end);
+            Ok((addresses, the_rest))
+        },
+        None => {
+            Err("Can't understand end of range.".to_owned().into())
+        },
+    }
+}
+
+
+fn address<'a>(state:&mut State, session:&mut Session, input:&'a str)
+        -> Result<Option<(usize, &'a str)>, Complaint> {
+    let input = input.trim_start();
+
+    let (mut address, mut the_rest) = match term(state, session, input)? {
+        Some(parsed) => parsed,
+        None => {
+            /* "+3" means ".+3" */
+            if input.starts_with('+') || input.starts_with('-') {
+                (state.index, input)
+            }
+            else {
+                return Ok(None);
+            }
+        },
+    };
+
+    loop {
+        let forward = match the_rest.chars().next() {
+            Some('+') => true,
+            Some('-') => false,
+            _ => {
+                break;
+            },
+        };
+        the_rest = &the_rest[1..];
+
+        let offset = match number(the_rest, state.prefs.radix)? {
+            Some((offset, after_offset)) => {
+                the_rest = after_offset;
+                offset
+            },
+            None => 1,
+        };
+
+        address = if forward {
+            match address.checked_add(offset) {
+                Some(address) => address,
+                None => {
+                    return Err(Complaint::new(Failure::BadRange,
+                            "Address is too big"));
+                },
+            }
+        }
+        else {
+            match address.checked_sub(offset) {
+                Some(address) => address,
+                None => {
+                    return Err(Complaint::new(Failure::BadRange,
+                            &format!("Going back {} bytes from {} would take \
+                            you beyond the 0th byte",
+                            ec::hex_unless_dec_with_radix(offset, state.prefs.radix),
+                            ec::hex_unless_dec_with_radix(address, state.prefs.radix))));
+                },
+            }
+        };
+    }
+
+    Ok(Some((address, the_rest)))
+}
+
+
+/// Everything an address can be before any offsets
+fn term<'a>(state:&mut State, session:&mut Session, input:&'a str)
+        -> Result<Option<(usize, &'a str)>, Complaint> {
+    match input.chars().next() {
+        Some('\'') => {
+            let mut chars = input[1..].chars();
+            match chars.next() {
+                Some(name) => {
+                    match session.marks.get(&name) {
+                        Some(index) => Ok(Some((*index, chars.as_str()))),
+                        None => Err(Complaint::new(Failure::NotFound,
+                                &format!("No mark '{}", name))),
+                    }
+                },
+                None => {
+                    Err("No mark given after '".to_owned().into())
+                },
+            }
+        },
+        Some('.') => {
+            Ok(Some((state.index, &input[1..])))
+        },
+        Some('$') => {
+            match state.max_index() {
+                Ok(max_index) => Ok(Some((max_index, &input[1..]))),
+                Err(error) => Err(Complaint::new(Failure::BadRange, &error)),
+            }
+        },
+        Some('/') => {
+            Ok(Some(search(state, session, &input[1..], true)?))
+        },
+        Some('?') => {
+            Ok(Some(search(state, session, &input[1..], false)?))
+        },
+        _ => {
+            number(input, state.prefs.radix)
+        },
+    }
+}
+
+
+/// Searches for the pattern up to the closing `/` (or `?` if not `forward`),
+/// or the last thing searched for if there isn't one.
+fn search<'a>(state:&mut State, session:&mut Session, input:&'a str,
+        forward:bool) -> Result<(usize, &'a str), Complaint> {
+    let delimiter = if forward {'/'} else {'?'};
+    let (given, the_rest) = search::split_pattern(input, delimiter);
+
+    let needle = if given.trim().is_empty() {
+        match search::last_needle(state, session) {
+            Some(needle) => needle,
+            None => {
+                return Err(Complaint::new(Failure::NotFound,
+                        "No previous search."));
+            },
+        }
+    }
+    else {
+        Needle::from_pattern(given)?
+    };
+    search::remember_needle(state, session, &needle);
+
+    match search::find_wrapping(&needle, &state.all_bytes, state.index,
+            forward, session) {
+        Some((index, _)) => {
+            Ok((index, the_rest))
+        },
+        None => {
+            Err(Complaint::not_found(&needle.to_string()))
+        },
+    }
+}
+
+
+/// A number in `radix` unless it starts with `0x` or `0d` followed by a hex or
+/// decimal digit.  So in hex, `0d10` is 10, but `0dk` is 0xd followed by `k`.
+fn number(input:&str, radix:u32)
+        -> Result<Option<(usize, &str)>, Complaint> {
+    let (radix, digits) = if has_radix_prefix(input, "0x", 16) {
+        (16, &input[2..])
+    }
+    else if has_radix_prefix(input, "0d", 10) {
+        (10, &input[2..])
+    }
+    else {
+        (radix, input)
+    };
+
+    let num_digits = digits.find(|c:char| !c.is_digit(radix))
+            .unwrap_or(digits.len());
+    if num_digits == 0 {
+        return Ok(None);
+    }
+
+    match usize::from_str_radix(&digits[..num_digits], radix) {
+        Ok(number) => Ok(Some((number, &digits[num_digits..]))),
+        Err(_) => Err(Complaint::new(Failure::BadRange,
+                &format!("{} is too big", &digits[..num_digits]))),
+    }
+}
+
+
+fn has_radix_prefix(input:&str, prefix:&str, radix:u32) -> bool {
+    input.starts_with(prefix) && match input[prefix.len()..].chars().next() {
+        Some(c) => c.is_digit(radix),
+        None => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn state(all_bytes:&[u8], index:usize) -> State {
+        State {
+            prefs: ec::Preferences::default(),
+            unsaved_changes: false,
+            filename: String::new(),
+            readonly: false,
+            last_search: None,
+            index,
+            all_bytes: all_bytes.to_vec(),
+            breaks: HashSet::new(),
+        }
+    }
+
+    fn failure<T>(result:Result<T, Complaint>) -> Failure {
+        match result {
+            Ok(_) => panic!("Expected a complaint"),
+            Err(complaint) => complaint.failure,
+        }
+    }
+
+    #[test]
+    fn test_term() {
+        let mut state = state(&[0x00, 0xde, 0xad, 0x00, 0xde, 0xad], 2);
+        let mut session = Session::default();
+        session.marks.insert('a', 4);
+        assert_eq!(term(&mut state, &mut session, ".p").unwrap(), Some((2, "p")));
+        assert_eq!(term(&mut state, &mut session, "$").unwrap(), Some((5, "")));
+        assert_eq!(term(&mut state, &mut session, "'ap").unwrap(), Some((4, "p")));
+        assert_eq!(term(&mut state, &mut session, "1f").unwrap(), Some((0x1f, "")));
+        assert_eq!(term(&mut state, &mut session, "p").unwrap(), None);
+        assert_eq!(failure(term(&mut state, &mut session, "'b")),
+                Failure::NotFound);
+        assert_eq!(failure(term(&mut state, &mut session, "'")), Failure::Parse);
+    }
+
+    #[test]
+    fn test_term_searches() {
+        let mut state = state(&[0x00, 0xde, 0xad, 0x00, 0xde, 0xad], 3);
+        let mut session = Session::default();
+        assert_eq!(term(&mut state, &mut session, "/dead/p").unwrap(),
+                Some((4, "p")));
+        assert_eq!(term(&mut state, &mut session, "?dead?").unwrap(),
+                Some((1, "")));
+
+        /* An empty pattern is whatever was last searched for */
+        assert_eq!(term(&mut state, &mut session, "//").unwrap(), Some((4, "")));
+        assert_eq!(failure(term(&mut state, &mut session, "/beef/")),
+                Failure::NotFound);
+    }
+
+    #[test]
+    fn test_offsets() {
+        let mut state = state(&[0; 0x20], 0x10);
+        let mut session = Session::default();
+        assert_eq!(address(&mut state, &mut session, "$-f").unwrap(),
+                Some((0x10, "")));
+        assert_eq!(address(&mut state, &mut session, ".+4-2p").unwrap(),
+                Some((0x12, "p")));
+        assert_eq!(address(&mut state, &mut session, " +").unwrap(),
+                Some((0x11, "")));
+        assert_eq!(address(&mut state, &mut session, "--").unwrap(),
+                Some((0xe, "")));
+        assert_eq!(address(&mut state, &mut session, "-0d10").unwrap(),
+                Some((6, "")));
+        assert_eq!(address(&mut state, &mut session, "p").unwrap(), None);
+        assert_eq!(failure(address(&mut state, &mut session, "-11")),
+                Failure::BadRange);
+        assert_eq!(failure(address(&mut state, &mut session,
+                &format!("{:x}+1", usize::MAX))), Failure::BadRange);
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        let mut state = state(&[0; 0x20], 0x10);
+        let mut session = Session::default();
+        assert_eq!(parse_addresses(&mut state, &mut session, "p").unwrap(),
+                (vec![], "p"));
+        assert_eq!(parse_addresses(&mut state, &mut session, "3p").unwrap(),
+                (vec![3], "p"));
+        assert_eq!(parse_addresses(&mut state, &mut session, "$-f,$p").unwrap(),
+                (vec![0x10, 0x1f], "p"));
+        assert_eq!(parse_addresses(&mut state, &mut session, "5 , 5k").unwrap(),
+                (vec![5, 5], "k"));
+        assert_eq!(failure(parse_addresses(&mut state, &mut session, "5,p")),
+                Failure::Parse);
+    }
+
+    #[test]
+    fn test_range_backward() {
+        let mut state = state(&[0; 0x20], 0x10);
+        let mut session = Session::default();
+        session.marks.insert('a', 2);
+        session.marks.insert('b', 5);
+        assert_eq!(failure(parse_addresses(&mut state, &mut session, "5,2p")),
+                Failure::BadRange);
+        assert_eq!(failure(parse_addresses(&mut state, &mut session, "'b,'a")),
+                Failure::BadRange);
+        assert_eq!(failure(parse_addresses(&mut state, &mut session, ".,.-1k")),
+                Failure::BadRange);
+    }
+
+    #[test]
+    fn test_number() {
+        assert_eq!(number("10", 16).unwrap(), Some((0x10, "")));
+        assert_eq!(number("10", 10).unwrap(), Some((10, "")));
+        assert_eq!(number("0d10", 16).unwrap(), Some((10, "")));
+        assert_eq!(number("0x10", 10).unwrap(), Some((0x10, "")));
+        assert_eq!(number("0x1fp", 10).unwrap(), Some((0x1f, "p")));
+        assert_eq!(number("0d19a", 16).unwrap(), Some((19, "a")));
+    }
+
+    #[test]
+    fn test_number_prefix_without_digits() {
+        /* In hex, 0d is just a number unless a decimal digit follows */
+        assert_eq!(number("0dk", 16).unwrap(), Some((0xd, "k")));
+        assert_eq!(number("0xp", 16).unwrap(), Some((0, "xp")));
+        assert_eq!(number("0x", 10).unwrap(), Some((0, "x")));
+        assert_eq!(number("p", 16).unwrap(), None);
+        assert!(number("0x1ffffffffffffffff", 16).is_err());
+    }
+}
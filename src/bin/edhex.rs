@@ -56,6 +56,24 @@ fn main() {
                         help text and state\nOnly print one line at a time \
                         until updated with 't' or 'T'\nThis is for clean output \
                         when piping commands in"))
+        .arg(Arg::with_name("script").short("f").long("script")
+                .takes_value(true).multiple(true).number_of_values(1)
+                .help("Run the commands in <script> before reading any from \
+                        STDIN\nLines starting with # are comments.  Can be \
+                        given more than once"))
+        .arg(Arg::with_name("command").short("e").long("expression")
+                .takes_value(true).multiple(true).number_of_values(1)
+                .help("Run <command> before reading any from STDIN\nCan be \
+                        given more than once.  Runs in order with -f"))
+        .arg(Arg::with_name("keep-going").short("k").long("keep-going")
+                .takes_value(false).help("Keep running commands from -e and \
+                        -f after one fails\nOtherwise exit with the status \
+                        --strict would"))
+        .arg(Arg::with_name("strict").long("strict")
+                .takes_value(false).help("Exit at the first error, wherever \
+                        the command came from, printing it to STDERR\nExit \
+                        status says what went wrong: 1 other, 2 can't parse, \
+                        3 bad range, 4 not found, 5 I/O, 6 read-only"))
         .arg(Arg::with_name("filename").required(false).help("Name of file to \
                 be edited.  If not given, a new file will be \
                 created on write"))
@@ -78,6 +96,36 @@ fn main() {
         None => default_state_path,
     };
 
+    /* Scripts and commands run in the order they're given */
+    let mut scripts_and_commands = vec![];
+    if let (Some(indices), Some(scripts)) =
+            (matches.indices_of("script"), matches.values_of("script")) {
+        for (i, script) in indices.zip(scripts) {
+            scripts_and_commands.push((i, true, script));
+        }
+    }
+    if let (Some(indices), Some(commands)) =
+            (matches.indices_of("command"), matches.values_of("command")) {
+        for (i, command) in indices.zip(commands) {
+            scripts_and_commands.push((i, false, command));
+        }
+    }
+    scripts_and_commands.sort();
+
+    let mut input = edhex::Input::new(matches.is_present("keep-going"),
+            matches.is_present("strict"));
+    for (_, is_script, script_or_command) in scripts_and_commands {
+        if is_script {
+            if let Err(error) = input.add_script(script_or_command) {
+                input.complain(edhex::Failure::Io, &error);
+                std::process::exit(edhex::Failure::Io.exit_status());
+            }
+        }
+        else {
+            input.add_command(script_or_command);
+        }
+    }
+
 
     if !filename_given {
         println!("No filename provided\nOpening empty buffer");
@@ -88,10 +136,12 @@ fn main() {
     }
 
     if filename_given && ec::path_exists(filename) && !ec::is_a_regular_file(filename) {
-        println!("{} isn't a regular file", filename);
-        std::process::exit(1);
+        input.complain(edhex::Failure::Io,
+                &format!("{} isn't a regular file", filename));
+        std::process::exit(edhex::Failure::Io.exit_status());
     }
 
     std::process::exit(edhex::actual_runtime(&filename, pipe_mode, color,
-            readonly, prefs_path.to_path_buf(), state_path.to_path_buf()))
+            readonly, prefs_path.to_path_buf(), state_path.to_path_buf(),
+            input))
 }
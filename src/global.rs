@@ -0,0 +1,168 @@
+use std::collections::VecDeque;
+
+
+/// A `g/pattern/command` or `v/pattern/command` being run, one range at a
+/// time, with the run loop asking for each next line.
+///
+/// Ranges are run first to last.  Whatever the command adds or kills is
+/// assumed to be where it ran, so the ranges still to go are moved by that
+/// much and any it killed are dropped.
+#[derive(Debug)]
+pub struct Global {
+    /// The `g/.../...` line, for the history
+    pub line: String,
+
+    /// What to run on each range, e.g. `k` or `I 4 00`
+    command: String,
+
+    /// Ranges still to run on, first to last
+    ranges: VecDeque<(usize, usize)>,
+
+    /// Where the command last ran and how many bytes there were then
+    last_run: Option<(usize, usize)>,
+
+    pub num_run: usize,
+
+    /// How many changes could be undone before starting, so every change
+    /// made can be undone as one
+    pub num_undos: usize,
+}
+
+
+impl Global {
+    /// `command` is run on every range in `ranges`, which must be in order.
+    /// An empty `command` prints.
+    pub fn new(line:&str, command:&str, ranges:Vec<(usize, usize)>,
+            num_undos:usize) -> Global {
+        let command = command.trim();
+        Global {
+            line: line.trim().to_owned(),
+            command: if command.is_empty() {"p".to_owned()} else {command.to_owned()},
+            ranges: ranges.into_iter().collect(),
+            last_run: None,
+            num_run: 0,
+            num_undos,
+        }
+    }
+
+
+    /// The next line to run, e.g. `0x10,0x13k`, now that there are
+    /// `num_bytes` bytes.  None when every range has been run on.
+    pub fn next_line(&mut self, num_bytes:usize) -> Option<String> {
+        if let Some((last_begin, num_bytes_then)) = self.last_run {
+            if num_bytes < num_bytes_then {
+                let num_killed = num_bytes_then - num_bytes;
+                self.ranges.retain(|range| range.0 >= last_begin + num_killed);
+                for range in self.ranges.iter_mut() {
+                    range.0 -= num_killed;
+                    range.1 -= num_killed;
+                }
+            }
+            else {
+                let num_added = num_bytes - num_bytes_then;
+                for range in self.ranges.iter_mut() {
+                    range.0 += num_added;
+                    range.1 += num_added;
+                }
+            }
+        }
+
+        let (begin, end) = self.ranges.pop_front()?;
+        self.last_run = Some((begin, num_bytes));
+        self.num_run += 1;
+        Some(format!("0x{:x},0x{:x}{}", begin, end, self.command))
+    }
+}
+
+
+/// The ranges within `range` of `matches` (offsets into the range and
+/// lengths, in order) if not `inverse`, otherwise the ranges between them
+pub fn ranges_of_matches(range:(usize, usize), matches:&[(usize, usize)],
+        inverse:bool) -> Vec<(usize, usize)> {
+    if !inverse {
+        return matches.iter().map(|(offset, len)|
+                (range.0 + offset, range.0 + offset + len - 1)).collect();
+    }
+
+    let mut ranges = vec![];
+    let mut begin = range.0;
+    for (offset, len) in matches {
+        let match_begin = range.0 + offset;
+        if match_begin > begin {
+            ranges.push((begin, match_begin - 1));
+        }
+        begin = std::cmp::max(begin, match_begin + len);
+    }
+    if begin <= range.1 {
+        ranges.push((begin, range.1));
+    }
+    ranges
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_line_unchanged() {
+        let mut global = Global::new("g/aa/p", "", vec![(1, 2), (5, 6)], 0);
+        assert_eq!(global.next_line(10), Some("0x1,0x2p".to_owned()));
+        assert_eq!(global.next_line(10), Some("0x5,0x6p".to_owned()));
+        assert_eq!(global.next_line(10), None);
+        assert_eq!(global.num_run, 2);
+    }
+
+    #[test]
+    fn test_next_line_kills() {
+        let mut global = Global::new("g/aa/k", "k", vec![(1, 2), (5, 6), (8, 9)],
+                0);
+        assert_eq!(global.next_line(10), Some("0x1,0x2k".to_owned()));
+        assert_eq!(global.next_line(8), Some("0x3,0x4k".to_owned()));
+        assert_eq!(global.next_line(6), Some("0x4,0x5k".to_owned()));
+        assert_eq!(global.next_line(4), None);
+    }
+
+    #[test]
+    fn test_next_line_overlapping_kills() {
+        /* Killing 0 - 1 takes the start of the match at 1 with it */
+        let mut global = Global::new("g/aaaa/k", "k",
+                vec![(0, 1), (1, 2), (2, 3), (6, 7)], 0);
+        assert_eq!(global.next_line(8), Some("0x0,0x1k".to_owned()));
+        assert_eq!(global.next_line(6), Some("0x0,0x1k".to_owned()));
+        assert_eq!(global.next_line(4), Some("0x2,0x3k".to_owned()));
+        assert_eq!(global.next_line(2), None);
+        assert_eq!(global.num_run, 3);
+
+        /* A command killing more than its range takes later ones with it */
+        let mut global = Global::new("g/aaaa/k", "k",
+                vec![(0, 1), (1, 2), (2, 3), (6, 7)], 0);
+        assert_eq!(global.next_line(8), Some("0x0,0x1k".to_owned()));
+        assert_eq!(global.next_line(5), Some("0x3,0x4k".to_owned()));
+        assert_eq!(global.next_line(3), None);
+    }
+
+    #[test]
+    fn test_next_line_inserts() {
+        let mut global = Global::new("g/aa/I 2 00", "I 2 00",
+                vec![(1, 1), (2, 2), (4, 4)], 0);
+        assert_eq!(global.next_line(5), Some("0x1,0x1I 2 00".to_owned()));
+        assert_eq!(global.next_line(7), Some("0x4,0x4I 2 00".to_owned()));
+        assert_eq!(global.next_line(9), Some("0x8,0x8I 2 00".to_owned()));
+        assert_eq!(global.next_line(11), None);
+    }
+
+    #[test]
+    fn test_ranges_of_matches() {
+        let matches = [(0, 2), (1, 2), (5, 1)];
+        assert_eq!(ranges_of_matches((0x10, 0x17), &matches, false),
+                vec![(0x10, 0x11), (0x11, 0x12), (0x15, 0x15)]);
+        assert_eq!(ranges_of_matches((0x10, 0x17), &matches, true),
+                vec![(0x13, 0x14), (0x16, 0x17)]);
+        assert_eq!(ranges_of_matches((0x10, 0x15), &[(0, 6)], true), vec![]);
+        assert_eq!(ranges_of_matches((0x10, 0x15), &[], true),
+                vec![(0x10, 0x15)]);
+        assert_eq!(ranges_of_matches((0x10, 0x15), &[(2, 1)], true),
+                vec![(0x10, 0x11), (0x13, 0x15)]);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::input::{Complaint, Failure};
+use crate::session::{Displaced, Session};
+use ec::State;
+use std::mem;
+
+
+/// Replacement of `old` bytes by `new` bytes starting at `index`.  Inserts
+/// have an empty `old` and kills have an empty `new`.
+#[derive(Debug)]
+struct Splice {
+    index: usize,
+    old: Vec<u8>,
+    new: Vec<u8>,
+
+    /// What moving marks and notes to match did to them
+    displaced: Displaced,
+}
+
+
+impl Splice {
+    fn redo(&mut self, all_bytes: &mut Vec<u8>, session:&mut Session) {
+        all_bytes.splice(self.index..(self.index + self.old.len()),
+                self.new.iter().cloned());
+        self.displaced = session.splice(self.index, self.old.len(),
+                self.new.len());
+    }
+
+
+    fn undo(&self, all_bytes: &mut Vec<u8>, session:&mut Session) {
+        all_bytes.splice(self.index..(self.index + self.new.len()),
+                self.old.iter().cloned());
+        session.unsplice(self.index, self.old.len(), self.new.len(),
+                &self.displaced);
+    }
+}
+
+
+/// Everything one command did to the bytes.  Undone and redone as a unit.
+#[derive(Debug)]
+struct Change {
+    command: String,
+    splices: Vec<Splice>,
+    index_before: usize,
+    index_after: usize,
+}
+
+
+impl Change {
+    fn summary(&self, radix:u32) -> String {
+        if self.splices.len() != 1 {
+            return format!("{} edits",
+                    ec::hex_unless_dec_with_radix(self.splices.len(), radix));
+        }
+
+        let splice = &self.splices[0];
+        let index = ec::hex_unless_dec_with_radix(splice.index, radix);
+        let num_old = ec::hex_unless_dec_with_radix(splice.old.len(), radix);
+        let num_new = ec::hex_unless_dec_with_radix(splice.new.len(), radix);
+        if splice.old.is_empty() {
+            format!("inserted {} byte(s) at {}", num_new, index)
+        }
+        else if splice.new.is_empty() {
+            format!("killed {} byte(s) at {}", num_old, index)
+        }
+        else if splice.old.len() == splice.new.len() {
+            format!("overwrote {} byte(s) at {}", num_new, index)
+        }
+        else {
+            format!("replaced {} byte(s) with {} at {}", num_old, num_new, index)
+        }
+    }
+}
+
+
+/// Undo/redo history of changes to `state.all_bytes`.
+///
+/// Commands make changes with `splice` and then `commit` them so that
+/// everything one command did is undone at once.  Nothing changes the bytes
+/// except through here, so this is where read-only mode is enforced.
+#[derive(Debug)]
+pub struct History {
+    undos: Vec<Change>,
+    redos: Vec<Change>,
+    pending: Vec<Splice>,
+    pending_index_before: usize,
+
+    /// How many changes had been made when the bytes were last written, or
+    /// None if there's no way back to what was written.
+    saved_at: Option<usize>,
+}
+
+
+impl History {
+    pub fn new(unsaved_changes:bool) -> History {
+        History {
+            undos: vec![],
+            redos: vec![],
+            pending: vec![],
+            pending_index_before: 0,
+            saved_at: if unsaved_changes {None} else {Some(0)},
+        }
+    }
+
+
+    /// Forget everything, e.g. after loading different bytes
+    pub fn clear(&mut self, unsaved_changes:bool) {
+        *self = History::new(unsaved_changes);
+    }
+
+
+    /// Call after the bytes have been written out
+    pub fn mark_saved(&mut self) {
+        self.saved_at = Some(self.undos.len());
+    }
+
+
+    /// Call when what's on disk no longer matches any point in the history,
+    /// e.g. after changing the filename
+    pub fn forget_saved(&mut self) {
+        self.saved_at = None;
+    }
+
+
+    fn unsaved_changes(&self) -> bool {
+        self.saved_at != Some(self.undos.len())
+    }
+
+
+    /// Replace `num_old` bytes at `index` with `new`.  Becomes part of the
+    /// change made by the next `commit`.
+    pub fn splice(&mut self, state:&mut State, index:usize, num_old:usize,
+            new:Vec<u8>) -> Result<(), Complaint> {
+        refuse_if_readonly(state)?;
+        if self.pending.is_empty() {
+            self.pending_index_before = state.index;
+        }
+        let old = state.all_bytes.splice(index..(index + num_old),
+                new.iter().cloned()).collect();
+        self.pending.push(Splice {
+            index,
+            old,
+            new,
+            displaced: Displaced::default(),
+        });
+        Ok(())
+    }
+
+
+    /// Record everything spliced since the last commit as one change made by
+    /// `command`, moving marks and notes to match.  Does nothing if nothing
+    /// was spliced.
+    pub fn commit(&mut self, state:&mut State, session:&mut Session,
+            command:&str) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        for splice in self.pending.iter_mut() {
+            splice.displaced = session.splice(splice.index, splice.old.len(),
+                    splice.new.len());
+        }
+
+        /* What was written is only reachable by redoing, which is now
+         * impossible */
+        if let Some(saved_at) = self.saved_at {
+            if saved_at > self.undos.len() {
+                self.saved_at = None;
+            }
+        }
+
+        self.undos.push(Change {
+            command: command.trim().to_owned(),
+            splices: mem::take(&mut self.pending),
+            index_before: self.pending_index_before,
+            index_after: state.index,
+        });
+        self.redos.clear();
+        state.unsaved_changes = self.unsaved_changes();
+    }
+
+
+    /// How many changes can be undone, e.g. to `combine_since`
+    pub fn num_undos(&self) -> usize {
+        self.undos.len()
+    }
+
+
+    /// Make every change committed since there were `num_undos` into one
+    /// made by `command`, so it's undone all at once
+    pub fn combine_since(&mut self, num_undos:usize, command:&str) {
+        if self.undos.len() <= num_undos {
+            return;
+        }
+
+        let changes = self.undos.split_off(num_undos);
+        if let Some(saved_at) = self.saved_at {
+            if saved_at > num_undos {
+                self.saved_at = None;
+            }
+        }
+        self.undos.push(Change {
+            command: command.trim().to_owned(),
+            index_before: changes[0].index_before,
+            index_after: changes[changes.len() - 1].index_after,
+            splices: changes.into_iter().flat_map(|change| change.splices)
+                    .collect(),
+        });
+    }
+
+
+    /// Returns the command which made the change that was undone
+    pub fn undo(&mut self, state:&mut State, session:&mut Session)
+            -> Result<String, Complaint> {
+        refuse_if_readonly(state)?;
+        match self.undos.pop() {
+            Some(change) => {
+                for splice in change.splices.iter().rev() {
+                    splice.undo(&mut state.all_bytes, session);
+                }
+                state.index = change.index_before;
+                let command = change.command.to_owned();
+                self.redos.push(change);
+                state.unsaved_changes = self.unsaved_changes();
+                Ok(command)
+            },
+            None => {
+                Err(Complaint::new(Failure::Other, "Nothing to undo"))
+            },
+        }
+    }
+
+
+    /// Returns the command which made the change that was redone
+    pub fn redo(&mut self, state:&mut State, session:&mut Session)
+            -> Result<String, Complaint> {
+        refuse_if_readonly(state)?;
+        match self.redos.pop() {
+            Some(mut change) => {
+                for splice in change.splices.iter_mut() {
+                    splice.redo(&mut state.all_bytes, session);
+                }
+                state.index = change.index_after;
+                let command = change.command.to_owned();
+                self.undos.push(change);
+                state.unsaved_changes = self.unsaved_changes();
+                Ok(command)
+            },
+            None => {
+                Err(Complaint::new(Failure::Other, "Nothing to redo"))
+            },
+        }
+    }
+
+
+    /// Oldest change first.  Changes that have been undone come last.
+    pub fn print(&self, radix:u32) {
+        if self.undos.is_empty() && self.redos.is_empty() {
+            println!("No changes");
+            return;
+        }
+
+        let undone = self.redos.iter().rev().map(|change| (change, true));
+        for (i, (change, is_undone)) in self.undos.iter()
+                .map(|change| (change, false)).chain(undone).enumerate() {
+            println!("{:>4}  {:<16} {}{}", ec::hex_unless_dec_with_radix(i + 1, radix),
+                    change.command, change.summary(radix),
+                    if is_undone {"  (undone)"} else {""});
+        }
+    }
+}
+
+
+fn refuse_if_readonly(state:&State) -> Result<(), Complaint> {
+    if state.readonly {
+        Err(Complaint::new(Failure::ReadOnly, "Read-only mode"))
+    }
+    else {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn state(all_bytes:&[u8]) -> State {
+        State {
+            prefs: ec::Preferences::default(),
+            unsaved_changes: false,
+            filename: String::new(),
+            readonly: false,
+            last_search: None,
+            index: 0,
+            all_bytes: all_bytes.to_vec(),
+            breaks: HashSet::new(),
+        }
+    }
+
+    fn failure<T>(result:Result<T, Complaint>) -> Failure {
+        match result {
+            Ok(_) => panic!("Expected a complaint"),
+            Err(complaint) => complaint.failure,
+        }
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut state = state(&[0xde, 0xad, 0xbe, 0xef]);
+        let mut session = Session::default();
+        let mut history = History::new(false);
+
+        /* Both splices are one change */
+        history.splice(&mut state, 1, 2, vec![]).unwrap();
+        history.splice(&mut state, 0, 0, vec![0x00, 0x01]).unwrap();
+        state.index = 2;
+        history.commit(&mut state, &mut session, "1,2k ");
+        assert_eq!(state.all_bytes, vec![0x00, 0x01, 0xde, 0xef]);
+        assert_eq!(history.num_undos(), 1);
+        assert!(state.unsaved_changes);
+
+        assert_eq!(history.undo(&mut state, &mut session).unwrap(), "1,2k");
+        assert_eq!(state.all_bytes, vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(state.index, 0);
+        assert!(!state.unsaved_changes);
+        assert!(history.undo(&mut state, &mut session).is_err());
+
+        assert_eq!(history.redo(&mut state, &mut session).unwrap(), "1,2k");
+        assert_eq!(state.all_bytes, vec![0x00, 0x01, 0xde, 0xef]);
+        assert_eq!(state.index, 2);
+        assert!(history.redo(&mut state, &mut session).is_err());
+    }
+
+    #[test]
+    fn test_commit_forgets_redos() {
+        let mut state = state(&[0xde, 0xad]);
+        let mut session = Session::default();
+        let mut history = History::new(false);
+        history.splice(&mut state, 0, 1, vec![0xbe]).unwrap();
+        history.commit(&mut state, &mut session, "Y");
+        history.undo(&mut state, &mut session).unwrap();
+
+        /* Nothing spliced, so nothing committed */
+        history.commit(&mut state, &mut session, "p");
+        assert_eq!(history.num_undos(), 0);
+
+        history.splice(&mut state, 2, 0, vec![0xef]).unwrap();
+        history.commit(&mut state, &mut session, "$i");
+        assert!(history.redo(&mut state, &mut session).is_err());
+        assert_eq!(state.all_bytes, vec![0xde, 0xad, 0xef]);
+    }
+
+    #[test]
+    fn test_combine_since() {
+        let mut state = state(&[0x00; 4]);
+        let mut session = Session::default();
+        let mut history = History::new(false);
+        for i in 0..3 {
+            history.splice(&mut state, i, 1, vec![0xff]).unwrap();
+            history.commit(&mut state, &mut session, "k");
+        }
+        history.combine_since(1, "g/00/Y");
+        assert_eq!(history.num_undos(), 2);
+
+        assert_eq!(history.undo(&mut state, &mut session).unwrap(), "g/00/Y");
+        assert_eq!(state.all_bytes, vec![0xff, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_saved() {
+        let mut state = state(&[0xde, 0xad]);
+        let mut session = Session::default();
+        let mut history = History::new(false);
+        history.splice(&mut state, 0, 1, vec![]).unwrap();
+        history.commit(&mut state, &mut session, "k");
+        history.mark_saved();
+        history.undo(&mut state, &mut session).unwrap();
+        assert!(state.unsaved_changes);
+        history.redo(&mut state, &mut session).unwrap();
+        assert!(!state.unsaved_changes);
+
+        /* What was written can't be got back to after undoing past it and
+         * making a different change */
+        history.undo(&mut state, &mut session).unwrap();
+        history.splice(&mut state, 0, 0, vec![0xef]).unwrap();
+        history.commit(&mut state, &mut session, "i");
+        history.undo(&mut state, &mut session).unwrap();
+        assert!(state.unsaved_changes);
+    }
+
+    #[test]
+    fn test_readonly() {
+        let mut state = state(&[0xde, 0xad]);
+        let mut session = Session::default();
+        let mut history = History::new(false);
+        history.splice(&mut state, 0, 1, vec![0xbe]).unwrap();
+        history.commit(&mut state, &mut session, "Y");
+
+        state.readonly = true;
+        assert_eq!(failure(history.splice(&mut state, 0, 2, vec![])),
+                Failure::ReadOnly);
+        assert_eq!(failure(history.undo(&mut state, &mut session)),
+                Failure::ReadOnly);
+        assert_eq!(state.all_bytes, vec![0xbe, 0xad]);
+
+        state.readonly = false;
+        history.undo(&mut state, &mut session).unwrap();
+        state.readonly = true;
+        assert_eq!(failure(history.redo(&mut state, &mut session)),
+                Failure::ReadOnly);
+        assert_eq!(state.all_bytes, vec![0xde, 0xad]);
+    }
+}
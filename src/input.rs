@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+
+
+/// What kind of thing went wrong, which decides the exit status when
+/// stopping because of it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    Other,
+    Parse,
+    BadRange,
+    NotFound,
+    Io,
+    ReadOnly,
+}
+
+
+impl Failure {
+    pub fn exit_status(self) -> i32 {
+        match self {
+            Failure::Other    => 1,
+            Failure::Parse    => 2,
+            Failure::BadRange => 3,
+            Failure::NotFound => 4,
+            Failure::Io       => 5,
+            Failure::ReadOnly => 6,
+        }
+    }
+}
+
+
+/// An error message along with what kind of error it is
+#[derive(Debug)]
+pub struct Complaint {
+    pub failure: Failure,
+    pub message: String,
+}
+
+
+impl Complaint {
+    pub fn new(failure:Failure, message:&str) -> Complaint {
+        Complaint {
+            failure,
+            message: message.to_owned(),
+        }
+    }
+
+
+    /// e.g. `Complaint::not_found("de ad")`
+    pub fn not_found(what:&str) -> Complaint {
+        Complaint {
+            failure: Failure::NotFound,
+            message: format!("{} not found", what),
+        }
+    }
+}
+
+
+/// Anything not otherwise classified is something that couldn't be parsed
+impl From<String> for Complaint {
+    fn from(message:String) -> Complaint {
+        Complaint {
+            failure: Failure::Parse,
+            message,
+        }
+    }
+}
+
+
+/// Where commands and answers to prompts come from.  Lines from scripts and
+/// `-e` come first, in the order they were given, then lines from STDIN.
+#[derive(Debug)]
+pub struct Input {
+    /// Lines not yet read, each with where it came from, e.g. `fix.edhex:3`,
+    /// and whether it's from a script, where blank lines and comments are
+    /// skipped when looking for a command
+    pending: VecDeque<(String, String, bool)>,
+
+    /// Where the command being run came from.  None if STDIN.
+    from: Option<String>,
+
+    /// Keep running commands from scripts after one goes wrong
+    keep_going: bool,
+
+    /// Stop at the first thing that goes wrong, wherever the command came
+    /// from, and complain on STDERR
+    strict: bool,
+
+    /// What went wrong with the command being run, if anything
+    failed: Option<Failure>,
+}
+
+
+impl Input {
+    pub fn new(keep_going:bool, strict:bool) -> Input {
+        Input {
+            pending: VecDeque::new(),
+            from: None,
+            keep_going,
+            strict,
+            failed: None,
+        }
+    }
+
+
+    /// Queue up every line of `filename`.  Blank ones and comments, which
+    /// start with `#`, are skipped where a command is expected but answer
+    /// prompts like any other line, e.g. a blank one to take the default.
+    pub fn add_script(&mut self, filename:&str) -> Result<(), String> {
+        let contents = match std::fs::read_to_string(filename) {
+            Ok(contents) => contents,
+            Err(error) => {
+                return Err(format!("Couldn't read script {}: {}", filename,
+                        error));
+            },
+        };
+
+        for (i, line) in contents.lines().enumerate() {
+            self.pending.push_back((format!("{}:{}", filename, i + 1),
+                    line.to_owned(), true));
+        }
+
+        Ok(())
+    }
+
+
+    pub fn add_command(&mut self, command:&str) {
+        self.pending.push_back((format!("-e '{}'", command),
+                command.to_owned(), false));
+    }
+
+
+    /// Whether there are lines left from scripts or `-e`, meaning there's no
+    /// point prompting for anything
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+
+    /// The next command to run.  The error is an exit code.
+    pub fn next_command(&mut self) -> Result<String, i32> {
+        self.failed = None;
+        while let Some((from, line, from_script)) = self.pending.pop_front() {
+            let trimmed = line.trim();
+            if from_script && (trimmed.is_empty() || trimmed.starts_with('#')) {
+                continue;
+            }
+            self.from = Some(from);
+            return Ok(line);
+        }
+
+        self.from = None;
+        ec::get_input_or_die()
+    }
+
+
+    /// Like `next_command` for a command edhex made up, e.g. for a match of
+    /// `g/pattern/command`.  It counts as coming from wherever the last one
+    /// did.
+    pub fn next_made_up_command(&mut self, line:String) -> String {
+        self.failed = None;
+        line
+    }
+
+
+    /// Whether the command just run went wrong
+    pub fn went_wrong(&self) -> bool {
+        self.failed.is_some()
+    }
+
+
+    /// A line answering a prompt.  The error is an exit code.
+    pub fn read_line(&mut self) -> Result<String, i32> {
+        match self.pending.pop_front() {
+            Some((_, line, _)) => Ok(line),
+            None => ec::get_input_or_die(),
+        }
+    }
+
+
+    /// Like `ec::read_string_from_user`, but only prints `prompt` if the
+    /// answer is coming from STDIN
+    pub fn read_string(&mut self, prompt:&str) -> Result<String, String> {
+        match self.pending.pop_front() {
+            Some((_, line, _)) => Ok(line.trim().to_owned()),
+            None => {
+                match ec::read_string_from_user(Some(prompt)) {
+                    Ok(string) => Ok(string),
+                    Err(error) => Err(format!("{:?}", error)),
+                }
+            },
+        }
+    }
+
+
+    /// Prints `? (message)`, saying which line of which script if the
+    /// command didn't come from STDIN.  Prints to STDERR if strict.
+    pub fn complain(&mut self, failure:Failure, message:&str) {
+        let complaint = match &self.from {
+            Some(from) => format!("{}: ? ({})", from, message),
+            None => format!("? ({})", message),
+        };
+        if self.strict {
+            eprintln!("{}", complaint);
+        }
+        else {
+            println!("{}", complaint);
+        }
+
+        if self.failed.is_none() {
+            self.failed = Some(failure);
+        }
+    }
+
+
+    /// If the command just run went wrong and nothing more should be run,
+    /// the exit status to stop with.  That's when strict or when the command
+    /// came from a script or `-e` and not told to keep going.
+    pub fn should_stop(&self) -> Option<i32> {
+        match self.failed {
+            Some(failure) => {
+                if self.strict || (self.from.is_some() && !self.keep_going) {
+                    Some(failure.exit_status())
+                }
+                else {
+                    None
+                }
+            },
+            None => None,
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use ec::State;
+
+
+/// Seconds from 1601-01-01, where FILETIMEs start, to 1970-01-01
+const FILETIME_TO_UNIX_SECONDS:i64 = 11_644_473_600;
+
+/// How many bytes are decoded as text
+const TEXT_BYTES:usize = 16;
+
+
+/// Prints the bytes starting at `index` decoded every way that's commonly
+/// useful: integers of each size and endianness, floats, timestamps, a LEB128
+/// varint and text.  Integers are in the current radix.
+pub fn print_inspection(state:&State, index:usize) {
+    let bytes = if index < state.all_bytes.len() {
+        &state.all_bytes[index..]
+    }
+    else {
+        &[]
+    };
+    let radix = state.prefs.radix;
+
+    println!("At {}", ec::hex_unless_dec_with_radix(index, radix));
+    for (size, name) in &[(1, "8"), (2, "16"), (4, "32"), (8, "64")] {
+        for big_endian in &[false, true] {
+            if *size == 1 && *big_endian {
+                continue;
+            }
+            let label = if *size == 1 {
+                name.to_string()
+            }
+            else {
+                format!("{} {}", name, if *big_endian {"be"} else {"le"})
+            };
+            match read_uint(bytes, *size, *big_endian) {
+                Some(unsigned) => {
+                    let signed = sign_extend(unsigned, *size);
+                    println!("u{:<9}{:<24}i{:<9}{}", label,
+                            int_with_radix(i128::from(unsigned), radix),
+                            label, int_with_radix(i128::from(signed), radix));
+                },
+                None => {
+                    println!("u{:<9}{:<24}i{:<9}-", label, "-", label);
+                },
+            }
+        }
+    }
+
+    for big_endian in &[false, true] {
+        let endian = if *big_endian {"be"} else {"le"};
+        let f32_s = match read_uint(bytes, 4, *big_endian) {
+            Some(bits) => float(f32::from_bits(bits as u32)),
+            None => "-".to_owned(),
+        };
+        let f64_s = match read_uint(bytes, 8, *big_endian) {
+            Some(bits) => float(f64::from_bits(bits)),
+            None => "-".to_owned(),
+        };
+        println!("f32 {}    {:<24}f64 {}    {}", endian, f32_s, endian, f64_s);
+    }
+
+    for size in &[4, 8] {
+        for big_endian in &[false, true] {
+            let endian = if *big_endian {"be"} else {"le"};
+            let time_s = match read_uint(bytes, *size, *big_endian) {
+                Some(seconds) => utc(sign_extend(seconds, *size)),
+                None => "-".to_owned(),
+            };
+            println!("time_t {} {} {}", 8 * size, endian, time_s);
+        }
+    }
+
+    let filetime_s = match read_uint(bytes, 8, false) {
+        Some(ticks) => {
+            utc((ticks / 10_000_000) as i64 - FILETIME_TO_UNIX_SECONDS)
+        },
+        None => "-".to_owned(),
+    };
+    println!("FILETIME  {}", filetime_s);
+
+    match leb128(bytes) {
+        Some((value, len)) => {
+            println!("LEB128    {} ({} byte(s))",
+                    int_with_radix(i128::from(value), radix), len);
+        },
+        None => {
+            println!("LEB128    -");
+        },
+    }
+
+    let text_bytes = &bytes[..std::cmp::min(bytes.len(), TEXT_BYTES)];
+    println!("UTF-8     {}", printable(&String::from_utf8_lossy(text_bytes)));
+    for big_endian in &[false, true] {
+        let units:Vec<u16> = text_bytes.chunks_exact(2).map(|pair|
+                if *big_endian {
+                    u16::from_be_bytes([pair[0], pair[1]])
+                }
+                else {
+                    u16::from_le_bytes([pair[0], pair[1]])
+                }).collect();
+        println!("UTF-16 {} {}", if *big_endian {"be"} else {"le"},
+                printable(&String::from_utf16_lossy(&units)));
+    }
+}
+
+
+/// The `size` byte unsigned integer at the start of `bytes` if there are
+/// enough bytes
+fn read_uint(bytes:&[u8], size:usize, big_endian:bool) -> Option<u64> {
+    if bytes.len() < size {
+        return None;
+    }
+
+    let mut value = 0;
+    for i in 0..size {
+        let byte = if big_endian {bytes[i]} else {bytes[size - 1 - i]};
+        value = (value << 8) | u64::from(byte);
+    }
+    Some(value)
+}
+
+
+fn sign_extend(value:u64, size:usize) -> i64 {
+    let unused_bits = 64 - 8 * size as u32;
+    ((value << unused_bits) as i64) >> unused_bits
+}
+
+
+/// Like `ec::hex_unless_dec_with_radix`, but for negative numbers too, e.g.
+/// `-0x80`
+fn int_with_radix(value:i128, radix:u32) -> String {
+    let sign = if value < 0 {"-"} else {""};
+    if radix == 16 {
+        format!("{}0x{:x}", sign, value.unsigned_abs())
+    }
+    else {
+        format!("{}0d{}", sign, value.unsigned_abs())
+    }
+}
+
+
+/// An unsigned LEB128 varint and how many bytes it took, if one ends within
+/// `bytes` and fits in 64 bits
+fn leb128(bytes:&[u8]) -> Option<(u64, usize)> {
+    let mut value:u64 = 0;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        let low_bits = u64::from(byte & 0x7f);
+        if i == 9 && low_bits > 1 {
+            return None;
+        }
+        value |= low_bits << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+
+/// `value` in scientific notation if it's too big or small to be worth
+/// writing out in full, e.g. `3.8204714e-38`
+fn float<F>(value:F) -> String
+        where F: Copy + Into<f64> + std::fmt::Display + std::fmt::LowerExp {
+    let magnitude = value.into().abs();
+    if magnitude != 0.0 && magnitude.is_finite()
+            && !(1e-4..1e16).contains(&magnitude) {
+        format!("{:e}", value)
+    }
+    else {
+        format!("{}", value)
+    }
+}
+
+
+/// e.g. `2021-03-04 05:06:07 UTC` for seconds since 1970
+fn utc(seconds:i64) -> String {
+    let days = seconds.div_euclid(86_400);
+    let seconds_today = seconds.rem_euclid(86_400);
+
+    /* Days since 1970-01-01 to a date, from Howard Hinnant's
+     * civil_from_days */
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+            - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4
+            - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {shifted_month + 3} else {shifted_month - 9};
+    let year = year_of_era + era * 400 + if month <= 2 {1} else {0};
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day,
+            seconds_today / 3600, seconds_today % 3600 / 60, seconds_today % 60)
+}
+
+
+/// `text` with control characters shown as `.`
+fn printable(text:&str) -> String {
+    text.chars().map(|c| if c.is_control() {'.'} else {c}).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_uint() {
+        let bytes = [0xde, 0xad, 0xbe, 0xef];
+        assert_eq!(read_uint(&bytes, 1, false), Some(0xde));
+        assert_eq!(read_uint(&bytes, 2, false), Some(0xadde));
+        assert_eq!(read_uint(&bytes, 4, true), Some(0xdeadbeef));
+        assert_eq!(read_uint(&bytes, 8, true), None);
+    }
+
+    #[test]
+    fn test_sign_extend() {
+        assert_eq!(sign_extend(0x7f, 1), 0x7f);
+        assert_eq!(sign_extend(0x80, 1), -0x80);
+        assert_eq!(sign_extend(0xff, 1), -1);
+        assert_eq!(sign_extend(0xdead, 2), -0x2153);
+        assert_eq!(sign_extend(0xffff_ffff, 4), -1);
+        assert_eq!(sign_extend(u64::MAX, 8), -1);
+    }
+
+    #[test]
+    fn test_int_with_radix() {
+        assert_eq!(int_with_radix(0xde, 16), "0xde");
+        assert_eq!(int_with_radix(-0x22, 16), "-0x22");
+        assert_eq!(int_with_radix(222, 10), "0d222");
+        assert_eq!(int_with_radix(i128::from(i64::MIN), 10),
+                "-0d9223372036854775808");
+    }
+
+    #[test]
+    fn test_leb128() {
+        assert_eq!(leb128(&[0x00]), Some((0, 1)));
+        assert_eq!(leb128(&[0x7f, 0xff]), Some((0x7f, 1)));
+        assert_eq!(leb128(&[0xe5, 0x8e, 0x26]), Some((624_485, 3)));
+        assert_eq!(leb128(&[0x80, 0x80]), None);
+        assert_eq!(leb128(&[]), None);
+
+        /* 64 bits is as big as it gets */
+        let mut max = vec![0xff; 9];
+        max.push(0x01);
+        assert_eq!(leb128(&max), Some((u64::MAX, 10)));
+        max[9] = 0x02;
+        assert_eq!(leb128(&max), None);
+    }
+
+    #[test]
+    fn test_utc() {
+        assert_eq!(utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(utc(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(utc(1_614_834_367), "2021-03-04 05:06:07 UTC");
+        assert_eq!(utc(-1), "1969-12-31 23:59:59 UTC");
+        assert_eq!(utc(i64::from(i32::MAX)), "2038-01-19 03:14:07 UTC");
+    }
+}
@@ -1,11 +1,19 @@
 use ansi_term::Color;
 use ec::State;
+use global::Global;
+use history::History;
+pub use input::{Failure, Input};
+use input::Complaint;
+use preferences::{Backup, MorePreferences};
+use search::Needle;
+use session::{Note, Session};
+use transform::Transform;
 use regex::Regex;
 use std::collections::HashSet;
 use std::io;
 use std::io::Write;
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 // TODO This is deprecated and should be
 // replaced with
@@ -14,11 +22,32 @@ use std::path::PathBuf;
 // work for after Rust 1.26.0  Far enough in the future, use the Cargo.toml way.
 extern crate edhex_core as ec;
 
+mod address;
+mod global;
+mod history;
+mod input;
+mod inspect;
+mod literal;
+mod preferences;
+mod render;
+mod search;
+mod session;
+mod transform;
+mod write;
+
+
+/// Report an error of kind `$failure`, which stops scripts unless told to
+/// keep going
+macro_rules! complain {
+    ($input:expr, $failure:expr, $($arg:tt)*) => {
+        $input.complain($failure, &format!($($arg)*))
+    };
+}
 
 macro_rules! skip_bad_range {
-    ($command:expr, $all_bytes:expr) => {
+    ($command:expr, $all_bytes:expr, $input:expr) => {
         if $command.bad_range(&$all_bytes) {
-            println!("? (bad range)");
+            complain!($input, Failure::BadRange, "bad range");
             continue;
         }
     };
@@ -48,11 +77,48 @@ j           (j)ump back to previous line of byte(s) and print
 +3d4        Move 3d4 bytes forward and print from there
 -3d4        Move 3d4 bytes back and print from there
 $           Move to last byte and print it
+$-10        Move to 10 bytes before the last byte and print from there
+0d100       Move to byte number 100 in decimal even if input is hex (0x for hex)
+/dead/+4    Move to 4 bytes after the next de ad and print from there
+?dead?,.p   (p)rint from the previous de ad through the current byte
 /deadbeef   If bytes de ad be ef exist after current index, move there and print
 ?deadbeef   If bytes de ad be ef exist before current index, move there and print
+/\"GET /\"   Move to the next GET / as UTF-8 text and print.  Quoted text,
+              typed numbers and escapes mix with hex as at the > prompt
+/\"get\"i    Move to the next get, GET, Get, etc. and print
+/t:hello    Move to the next hello as UTF-8 text; (ti:hello ignores case)
+/u:hello    Move to the next hello as UTF-16LE text; (ui:hello ignores case)
+/U:hello    Move to the next hello as UTF-16BE text; (Ui:hello ignores case)
+/de ?? ef   Move to the next de, any byte, then ef and print.  ? or . is any
+              nibble, e.g. 4? or 4. for 40 - 4f.  Searching backward, use .
+/de [2-4] ef  Move to the next de followed by 2 to 4 (decimal) bytes of
+              anything then ef and print.  [3] jumps exactly 3 bytes
+/r:\\x7fELF.{{12}}\\x02/  Move to the next match of a regular expression over the
+              raw bytes and print.  . matches any byte, \\xff the byte ff and
+              \\/ a /.  Works anywhere a search does, e.g. /r:PK\\x03\\x04/,+f
+/dead/l     (l)ist every de ad, printing where each is and the row it's in.
+              //l lists matches of the last search
+/dead/=     Print how many times de ad occurs; //= for the last search
+1d,72/dead/l  (l)ist every de ad lying within bytes 1d - 72; 1d/dead/= counts
+              those from byte 1d on
+g/dead/k    Run k on each de ad, i.e. (k)ill every de ad, and print how many
+              times it ran.  Any command works, run with each match as its
+              range, e.g. g/dead/p, g/dead/I 4 00 or g/dead/~xor ff.  All the
+              changes are undone at once.  1d,72g/dead/k only looks in 1d - 72
+v/00/p      Run p on everything between 00s, i.e. (p)rint every run of bytes
+              that aren't 00
 /           Perform last search again starting at next byte
 ?           Perform last search (backwards) again starting at previous byte
+              Where the last search matches is highlighted in printed bytes,
+              the current match in yellow, or in [brackets] and {{braces}}
+              without color unless in pipe mode
 k           Delete/(k)ill byte at current index and print new line of byte(s)
+K ~         The first time a file is written over, bac(K) up what was there
+              to file~, which later writes leave alone
+K time      Every time a file is written over, bac(K) up what was there to
+              file.1700000000~ (seconds since 1970), one backup per write
+K off       Don't bac(K) up before writing [Default]
+K           Print how files are bac(K)ed up
 7dk         Move to byte 7d, (k)ill that byte, and print from there.
 1d,72k      Move to byte 1d; (k)ill bytes 1d - 72 inclusive; print from there
 /deadbeef/k If bytes de ad be ef exist after current index, move there,
@@ -61,18 +127,79 @@ i           Prompt you to enter bytes which will be (i)nserted at current index
 72i         Move to byte number 72; prompt you to enter bytes to (i)nsert there
 /deadbeef/i If bytes de ad be ef exist after current index, move there
               and prompt you to enter bytes which will be (i)nserted there
+I 10 00     (I)nsert 10 copies of 00 at the current index
+72I 3 dead  Move to byte number 72 and (I)nsert 3 copies of de ad there
+100,1ff* 00 Fill bytes 100 - 1ff inclusive with 00 (F would look like a number)
+100,1ff* deadbeef
+            Fill bytes 100 - 1ff inclusive with de ad be ef de ad be ef ...
+1d,72~xor 5a
+            XOR bytes 1d - 72 inclusive with 5a.  Longer keys like dead repeat
+              across the range.  Without a range, just the current byte.
+1d,72~add 3 Add 3 to each of bytes 1d - 72, wrapping around past ff
+1d,72~sub 3 Subtract 3 from each of bytes 1d - 72, wrapping around past 0
+1d,72~add16le 1
+            Add 1 to each little-endian 16 bit word in bytes 1d - 72.  Also
+              add16be, add32le, add32be, add64le, add64be and likewise sub
+1d,72~not   Flip every bit of bytes 1d - 72
+1d,72~rol 3 Rotate the bits of each of bytes 1d - 72 left by 3 (ror for right)
+1d,72~nibbles
+            Swap the high and low nibbles of each of bytes 1d - 72
+1d,72~swap4 Reverse the order of the bytes in each 4 byte word in bytes 1d - 72,
+              e.g. to make big-endian words little-endian.  Also swap2, swap8
+1d,72~reverse
+            Reverse the order of bytes 1d - 72
+O           Prompt you to enter bytes which will (O)verwrite those at current
+              index, extending the file if they run past the end
+72O         Move to byte number 72; prompt you to enter bytes to (O)verwrite there
+1d,72O      Move to byte 1d; prompt you to enter bytes to (O)verwrite bytes
+              1d - 72.  Fails if too many bytes are entered.
+/deadbeef/O If bytes de ad be ef exist after current index, move there
+              and prompt you to enter bytes to (O)verwrite there
+            At the > prompt for bytes to insert or overwrite with, hex like
+              de ad can be mixed with numbers like u32le:1234, i16be:-5,
+              u8:0xff or f32:3.14, \"quoted text\", u\"UTF-16 text\" and
+              escapes like \\x00\\n
 12,3dp      (p)rint bytes 12 - 3d inclusive, move to byte 12
-l           (l)oad a new file.
-L           (L)oad state from a file.  Fails if file you were editing is gone.
+s/de/beef/  (s)ubstitute beef for the first de in the file and say how many
+s/de/beef/g (s)ubstitute beef for every de in the file
+1d,72s/de/beef/g
+            (s)ubstitute beef for every de lying within bytes 1d - 72
+l           (l)oad a new file, forgetting marks, notes and registers.
+L           (L)oad state (including marks, notes and registers) from a file.
+              Fails if file you were editing is gone.
 m           Toggle whether or not characters are printed after bytes
+Ma          (M)ark the current byte as 'a
+3d4Ma       (M)ark byte 3d4 as 'a
+M-a         Remove the (M)ark 'a
+M           List all (M)arks
+'a          Move to the byte marked 'a and print from there.
+              'a can be used anywhere a byte number can, e.g. 'a,'b+4p
 n           Toggle whether or not byte (n)umbers are printed before bytes
+N entry pt  Attach the (N)ote 'entry pt' to the current byte
+1d,72N hdr  Attach the (N)ote 'hdr' to bytes 1d - 72
+N-          Remove (N)otes touching the current byte (or range given)
+N           List all (N)otes.  Marks and notes are also printed under bytes.
 o           Toggle using c(o)lor
 p           (p)rint current line of byte(s) (depending on 'W')
 P           Save (P)references to file (width, color, etc.)
+=           Print the bytes at the current index decoded as integers of each
+              size and endianness, floats, timestamps, LEB128 and text
+72=         Print what the bytes at byte 72 decode to
+==          Toggle printing what the current bytes decode to after printing
+J           Toggle searches (J)umping to the other end of the bytes when they
+              reach one end, saying \"Search wrapped\" when they do (on STDERR
+              with -P).  P saves this
 r           (r)ead preferences from a file.
+r blob.bin  (r)ead the bytes of blob.bin and insert them at the current index
+72r blob.bin
+            (r)ead the bytes of blob.bin and insert them at byte 72
+72rO blob.bin
+            (r)ead the bytes of blob.bin and (O)verwrite from byte 72 on,
+              extending the file if they run past the end
 R           Toggle (R)ead-only mode
 s           Print (s)tate of toggles, 'W'idth, etc.
-S           (S)ave state to a file except the bytes you're editing.
+S           (S)ave state (including marks, notes and registers) to a file
+              except the bytes you're editing.
 t3d         Print 0x3d lines of con(t)extual bytes after current line [Default {}]
 T3d         Print 0x3d lines of con(T)extual bytes before current line [Default {}]
 u           (u)pdate filename to write to
@@ -81,8 +208,26 @@ v           Insert a (v)isual break in the display at the current byte.
             NOTE: This does not insert a byte in the file.  It's just display.
 V           Remove a (V)isual break if one is at the current byte.
 x           Toggle reading input and displaying output as he(x) or decimal
-w           Actually (w)rite changes to the file on disk
+w           Actually (w)rite changes to the file on disk.  They're written to
+              a temporary file first so a crash can't leave half a file.
+w out.bin   (w)rite all the bytes to out.bin, but keep writing to the file on
+              disk with a plain 'w'
+1d,72w out.bin
+            (w)rite bytes 1d - 72 inclusive to out.bin
+1d,72W out.bin
+            Append bytes 1d - 72 inclusive to the end of out.bin ((W)rite more)
 W3d         Set (W)idth to 0x3d.  i.e. print a linebreak every 3d bytes [Default {}]
+y           List registers and the first few bytes in each
+ya          (y)ank the current byte into register a
+1d,72ya     (y)ank bytes 1d - 72 inclusive into register a
+Ya          Put the bytes in register a at the current index (Y is y's opposite)
+72Ya        Put the bytes in register a at byte 72
+72YOa       Put the bytes in register a at byte 72, (O)verwriting what's there
+1d,72X 200  Move bytes 1d - 72 inclusive so they start where byte 200 is.
+              Any byte number works, e.g. 1d,72X 'a
+z           Undo the last change to the bytes
+Z           Redo the last change undone with 'z'
+zh          Print (h)istory of changes which can be undone or redone
 q           (q)uit
 ",
     ec::hex_unless_dec_with_radix(DEFAULT_BEFORE_CONTEXT, state.prefs.radix),
@@ -91,17 +236,19 @@ q           (q)uit
 );
 }
 
-fn read_bytes_from_user() -> Result<Vec<u8>, String> {
-    print!("> ");
-    io::stdout().flush().unwrap();
-    let input = match ec::get_input_or_die() {
-        Ok(input) => input,
+fn read_bytes_from_user(input:&mut Input) -> Result<Vec<u8>, Complaint> {
+    if !input.has_pending() {
+        print!("> ");
+        io::stdout().flush().unwrap();
+    }
+    let line = match input.read_line() {
+        Ok(line) => line,
         Err(_errcode) => {
-            return Err("Couldn't read input".to_owned());
+            return Err(Complaint::new(Failure::Io, "Couldn't read input"));
         }
     };
 
-    ec::bytes_from_string(&input)
+    Ok(literal::bytes_from_input(&line)?)
 }
 
 
@@ -111,37 +258,137 @@ impl Command {
     }
 
 
-    fn from_state_and_line(state:&mut State, line: &str) -> Result<Command, String> {
+    /// `line` is what's left after any addresses, e.g. `s/de/beef/g`
+    fn substitute(range:(usize, usize), line:&str) -> Result<Command, Complaint> {
+        let re_substitute = Regex::new(r"^ *s/(?P<needle>[0-9a-fA-F]+)/(?P<replacement>[0-9a-fA-F]*)(/(?P<global>g?))? *$").unwrap();
+        let caps = match re_substitute.captures(line) {
+            Some(caps) => caps,
+            None => {
+                return Err(format!("Unable to parse '{}'", line.trim()).into());
+            },
+        };
+
+        let mut args = vec![
+            caps.name("needle").unwrap().as_str().to_owned(),
+            caps.name("replacement").unwrap().as_str().to_owned(),
+        ];
+        if let Some(global) = caps.name("global") {
+            if global.as_str() == "g" {
+                args.push("g".to_owned());
+            }
+        }
+
+        Ok(Command{
+            range,
+            command: '⇄',
+            args,
+        })
+    }
+
+
+    /// `line` is what's left after any addresses, e.g. `/deadbeef/l` to list
+    /// or `//=` to count matches of the last search
+    fn search_list(range:(usize, usize), line:&str) -> Result<Command, Complaint> {
+        let re_search_list = Regex::new(&format!(r"^ */(?P<pattern>{})?/(?P<command>[l=]) *$",
+                search::pattern_regex('/'))).unwrap();
+        let caps = match re_search_list.captures(line) {
+            Some(caps) => caps,
+            None => {
+                return Err(format!("Unable to parse '{}'", line.trim()).into());
+            },
+        };
+
+        Ok(Command{
+            range,
+            command: if &caps["command"] == "l" {'≡'} else {'№'},
+            args: caps.name("pattern").map(|pattern| pattern.as_str().to_owned())
+                    .into_iter().collect(),
+        })
+    }
+
+
+    /// `line` is what's left after any addresses, e.g. `g/dead/k` to kill
+    /// every de ad or `v/00/p` to print everything that isn't 00.  The
+    /// command to run comes first in the args, then the pattern if there is
+    /// one.
+    fn global(range:(usize, usize), line:&str) -> Result<Command, Complaint> {
+        let re_global = Regex::new(&format!(r"^ *(?P<which>[gv])/(?P<pattern>{})?/(?P<command>.*)$",
+                search::pattern_regex('/'))).unwrap();
+        let caps = match re_global.captures(line) {
+            Some(caps) => caps,
+            None => {
+                return Err(format!("Unable to parse '{}'", line.trim()).into());
+            },
+        };
+
+        let mut args = vec![caps["command"].trim().to_owned()];
+        if let Some(pattern) = caps.name("pattern") {
+            args.push(pattern.as_str().to_owned());
+        }
+        Ok(Command{
+            range,
+            command: if &caps["which"] == "g" {'⊛'} else {'⊘'},
+            args,
+        })
+    }
+
+
+    /// Go to what `search::find_wrapping` found
+    fn go_to_found(found:Option<(usize, usize)>, needle:&Needle)
+            -> Result<Command, Complaint> {
+        match found {
+            Some((index, _)) => {
+                Ok(Command{
+                    range: (index, index),
+                    command: 'g',
+                    args: vec![],
+                })
+            },
+            None => {
+                Err(Complaint::not_found(&needle.to_string()))
+            },
+        }
+    }
+
+
+    fn from_state_and_line(state:&mut State, session:&mut Session, line: &str)
+            -> Result<Command, Complaint> {
         // TODO Make these constants outside of this function so they don't get
         // created over and over
         // TODO Allow general whitespace, not just literal spaces
         let re_blank_line = Regex::new(r"^ *$").unwrap();
         let re_pluses = Regex::new(r"^ *(?P<pluses>\++) *$").unwrap();
         let re_minuses = Regex::new(r"^ *(?P<minuses>\-+) *$").unwrap();
-        let re_search = Regex::new(r"^ *(?P<direction>[/?]) *(?P<bytes>[0-9a-fA-F]+) *$").unwrap();
+        let re_search = Regex::new(&format!(r"^ *(?:/(?P<forward>{})|\?(?P<backward>{}))$",
+                search::pattern_regex('/'), search::pattern_regex('?'))).unwrap();
         let re_search_again = Regex::new(r"^ *(?P<direction>[/?]) *$").unwrap();
-        let re_search_kill = Regex::new(r"^ */(?P<bytes>[0-9a-fA-F]+)/k *$").unwrap();
-        let re_search_insert = Regex::new(r"^ */(?P<bytes>[0-9a-fA-F]+)/i *$").unwrap();
-        let re_single_char_command = Regex::new(r"^ *(?P<command>[hijkmnopqRrsSlLPuUvVwx]).*$").unwrap();
-        let re_range = Regex::new(r"^ *(?P<begin>[0-9a-fA-F.$]+) *, *(?P<end>[0-9a-fA-F.$]+) *(?P<the_rest>.*) *$").unwrap();
-        let re_specified_index = Regex::new(r"^ *(?P<index>[0-9A-Fa-f.$]+) *(?P<the_rest>.*) *$").unwrap();
-        let re_offset_index = Regex::new(r"^ *(?P<sign>[-+])(?P<offset>[0-9A-Fa-f]+) *(?P<the_rest>.*) *$").unwrap();
+        let re_search_kill = Regex::new(&format!(r"^ */(?P<pattern>{})/k *$",
+                search::pattern_regex('/'))).unwrap();
+        let re_search_insert = Regex::new(&format!(r"^ */(?P<pattern>{})/(?P<command>[iO]) *$",
+                search::pattern_regex('/'))).unwrap();
+        let re_search_list = Regex::new(&format!(r"^ */(?:{})?/[l=] *$",
+                search::pattern_regex('/'))).unwrap();
+        let re_substitute = Regex::new(r"^ *s/").unwrap();
+        let re_global = Regex::new(r"^ *[gv]/").unwrap();
+        let re_single_char_command = Regex::new(r"^ *(?P<command>[hijIJkKmMnNopqORrsSlLPuUvVwxXyYzZ*~=])(?P<the_rest>.*)$").unwrap();
+        let re_addressed = Regex::new(r"^ *[0-9A-Fa-f.$+\-/?']").unwrap();
         let re_matches_nothing = Regex::new(r"^a\bc").unwrap();
         let re_width = Regex::new(r"^ *W *(?P<width>[0-9A-Fa-f]+) *$").unwrap();
         let re_before_context = Regex::new(r"^ *T *(?P<before_context>[0-9A-Fa-f]+) *$").unwrap();
         let re_after_context = Regex::new(r"^ *t *(?P<after_context>[0-9A-Fa-f]+) *$").unwrap();
 
         let is_blank_line          = re_blank_line.is_match(line);
+        let is_substitute          = re_substitute.is_match(line);
+        let is_global              = re_global.is_match(line);
         let is_single_char_command = re_single_char_command.is_match(line);
         let is_pluses              = re_pluses.is_match(line);
         let is_minuses             = re_minuses.is_match(line);
-        let is_range               = re_range.is_match(line);
-        let is_search              = re_search.is_match(line);
+        let is_addressed           = re_addressed.is_match(line);
         let is_search_again        = re_search_again.is_match(line);
+        let is_search              = re_search.is_match(line) && !is_search_again;
         let is_search_kill         = re_search_kill.is_match(line);
         let is_search_insert       = re_search_insert.is_match(line);
-        let is_specified_index     = re_specified_index.is_match(line);
-        let is_offset_index        = re_offset_index.is_match(line);
+        let is_search_list         = re_search_list.is_match(line);
         let is_width               = re_width.is_match(line);
         let is_before_context      = re_before_context.is_match(line);
         let is_after_context       = re_after_context.is_match(line);
@@ -149,6 +396,9 @@ impl Command {
         let re = if is_blank_line {
             re_blank_line
         }
+        else if is_substitute {
+            re_substitute
+        }
         else if is_single_char_command {
             re_single_char_command
         }
@@ -170,14 +420,8 @@ impl Command {
         else if is_minuses {
             re_minuses
         }
-        else if is_range {
-            re_range
-        }
-        else if is_specified_index {
-            re_specified_index
-        }
-        else if is_offset_index {
-            re_offset_index
+        else if is_addressed {
+            re_addressed
         }
         else if is_before_context {
             re_before_context
@@ -203,129 +447,103 @@ impl Command {
             })
         }
 
+        else if is_substitute {
+            if state.empty() {
+                return Err(Complaint::new(Failure::BadRange, "Empty file"));
+            }
+
+            Command::substitute((0, state.max_index()?), line)
+        }
+
+        else if is_global {
+            if state.empty() {
+                return Err(Complaint::new(Failure::BadRange, "Empty file"));
+            }
+
+            Command::global((0, state.max_index()?), line)
+        }
+
+        else if is_search_list {
+            if state.empty() {
+                return Err(Complaint::new(Failure::BadRange, "Empty file"));
+            }
+
+            Command::search_list((0, state.max_index()?), line)
+        }
+
         else if is_search_insert {
-            match ec::bytes_from_string(caps.unwrap().name("bytes").unwrap().as_str()) {
-                Ok(needle) => {
-                    if let Some(offset) = ec::index_of_bytes(&needle, &state.all_bytes[state.index..], true) {
-                        Ok(Command{
-                            range: (state.index + offset, state.index + offset),
-                            command: 'i',
-                            args: vec![],
-                        })
-                    }
-                    else {
-                        Err(format!("{} not found", ec::string_from_bytes(&needle)))
-                    }
+            let caps = caps.unwrap();
+            let command = caps.name("command").unwrap().as_str().chars().next().unwrap();
+            let needle = Needle::from_pattern(caps.name("pattern").unwrap().as_str())?;
+            search::remember_needle(state, session, &needle);
+            match search::find_wrapping(&needle, &state.all_bytes, state.index,
+                    true, session) {
+                Some((index, _)) => {
+                    Ok(Command{
+                        range: (index, index),
+                        command,
+                        args: vec![],
+                    })
+                },
+                None => {
+                    Err(Complaint::not_found(&needle.to_string()))
                 },
-                Err(error) => {
-                    Err(error)
-                }
             }
         }
 
         else if is_search_kill {
-            match ec::bytes_from_string(caps.unwrap().name("bytes").unwrap().as_str()) {
-                Ok(needle) => {
-                    let needle_num_bytes = if needle.len() == 0 {
-                        return Err("Searching for empty string".to_owned());
-                    }
-                    else {
-                        needle.len()
-                    };
-
-                    if let Some(offset) = ec::index_of_bytes(&needle, &state.all_bytes[state.index..], true) {
-                        Ok(Command{
-                            range: (state.index + offset, state.index + offset + needle_num_bytes - 1),
-                            command: 'k',
-                            args: vec![],
-                        })
-                    }
-                    else {
-                        Err(format!("{} not found", ec::string_from_bytes(&needle)))
-                    }
+            let needle = Needle::from_pattern(caps.unwrap().name("pattern").unwrap().as_str())?;
+            search::remember_needle(state, session, &needle);
+            match search::find_wrapping(&needle, &state.all_bytes, state.index,
+                    true, session) {
+                Some((index, len)) => {
+                    Ok(Command{
+                        range: (index, index + len - 1),
+                        command: 'k',
+                        args: vec![],
+                    })
+                },
+                None => {
+                    Err(Complaint::not_found(&needle.to_string()))
                 },
-                Err(error) => {
-                    Err(error)
-                }
             }
         }
 
         else if is_search_again {
-            if state.last_search.is_none() {
-                return Err(format!("No previous search."));
-            }
-
-            let needle = state.last_search.to_owned().unwrap();
+            let needle = match search::last_needle(state, session) {
+                Some(needle) => needle,
+                None => {
+                    return Err(Complaint::new(Failure::NotFound,
+                            "No previous search."));
+                },
+            };
 
             let caps = caps.unwrap();
             let forward = caps.name("direction").unwrap().as_str() == "/";
 
             /* Notice looking after current byte */
-            let haystack = if forward {
-                &state.all_bytes[(state.index + 1)..]
+            let from = if forward {
+                state.index + 1
             }
             else {
-                &state.all_bytes[..(state.index.saturating_sub(1))]
+                state.index.saturating_sub(1)
             };
 
-            if let Some(offset) = ec::index_of_bytes(&needle, haystack, forward) {
-                if forward {
-                    Ok(Command{
-                        range: (state.index + 1 + offset, state.index + 1 + offset),
-                        command: 'g',
-                        args: vec![],
-                    })
-                }
-                else {
-                    Ok(Command{
-                        range: (offset, offset),
-                        command: 'g',
-                        args: vec![],
-                    })
-                }
-            }
-            else {
-                Err(format!("{} not found", ec::string_from_bytes(&needle)))
-            }
+            Command::go_to_found(search::find_wrapping(&needle, &state.all_bytes,
+                    from, forward, session), &needle)
         }
 
         else if is_search {
             let caps = caps.unwrap();
-            let forward = caps.name("direction").unwrap().as_str() == "/";
-            match ec::bytes_from_string(caps.name("bytes").unwrap().as_str()) {
-                Ok(needle) => {
-                    state.last_search = Some(needle.to_owned());
+            let (forward, pattern) = match caps.name("forward") {
+                Some(pattern) => (true, pattern.as_str()),
+                None => (false, caps.name("backward").unwrap().as_str()),
+            };
+            let needle = Needle::from_pattern(pattern)?;
+            search::remember_needle(state, session, &needle);
 
-                    let haystack = if forward {
-                        &state.all_bytes[state.index..]
-                    }
-                    else {
-                        &state.all_bytes[..state.index]
-                    };
-                    if let Some(offset) = ec::index_of_bytes(&needle, haystack, forward) {
-                        if forward {
-                            Ok(Command{
-                                range: (state.index + offset, state.index + offset),
-                                command: 'g',
-                                args: vec![],
-                            })
-                        }
-                        else {
-                            Ok(Command{
-                                range: (offset, offset),
-                                command: 'g',
-                                args: vec![],
-                            })
-                        }
-                    }
-                    else {
-                        Err(format!("{} not found", ec::string_from_bytes(&needle)))
-                    }
-                },
-                Err(error) => {
-                    Err(error)
-                }
-            }
+            Command::go_to_found(search::find_wrapping(&needle, &state.all_bytes,
+                    state.index, forward, session), &needle)
         }
 
         else if is_minuses {
@@ -338,7 +556,10 @@ impl Command {
         }
 
         else if is_single_char_command {
-            let command = caps.unwrap().name("command").unwrap().as_str().chars().next().unwrap();
+            let caps = caps.unwrap();
+            let command = caps.name("command").unwrap().as_str().chars().next().unwrap();
+            let args = caps.name("the_rest").unwrap().as_str()
+                    .split_whitespace().map(|x| x.to_owned()).collect();
             if command == 'p' {
                 Ok(Command{
                     range: (state.index, state.index),
@@ -350,7 +571,7 @@ impl Command {
                 Ok(Command{
                     range: (state.index, state.index),
                     command: command,
-                    args: vec![],
+                    args,
                 })
             }
         }
@@ -374,7 +595,7 @@ impl Command {
               })
             }
             else {
-                Err(format!("Can't interpret {} as a number", given))
+                Err(format!("Can't interpret {} as a number", given).into())
             }
         }
 
@@ -389,7 +610,7 @@ impl Command {
               })
             }
             else {
-                Err(format!("Can't interpret {} as a number", given))
+                Err(format!("Can't interpret {} as a number", given).into())
             }
         }
 
@@ -404,163 +625,238 @@ impl Command {
               })
             }
             else {
-                Err("Width must be positive".to_owned())
+                Err("Width must be positive".to_owned().into())
             }
         }
 
-        else if is_range {
+        else if is_addressed {
             if state.empty() {
-                return Err("Empty file".to_owned());
+                return Err(Complaint::new(Failure::BadRange, "Empty file"));
             }
 
-            let _max_index = match state.max_index() {
-                Ok(max) => max,
-                Err(error) => {
-                    return Err(format!("? ({})", error));
+            let (addresses, the_rest) = address::parse_addresses(state,
+                    session, line)?;
+            let the_rest = the_rest.trim();
+            match *addresses.as_slice() {
+                [index] => {
+                    if the_rest.is_empty() {
+                        Ok(Command{
+                            range: (index, index),
+                            command: 'g',
+                            args: vec![],
+                        })
+                    }
+                    else if the_rest.starts_with("s/") {
+                        Command::substitute((index, state.max_index()?), the_rest)
+                    }
+                    else if the_rest.starts_with('/') {
+                        Command::search_list((index, state.max_index()?), the_rest)
+                    }
+                    else if the_rest.starts_with("g/") || the_rest.starts_with("v/") {
+                        Command::global((index, state.max_index()?), the_rest)
+                    }
+                    else {
+                        let command = the_rest.chars().next().unwrap();
+                        let args = the_rest[command.len_utf8()..].split_whitespace()
+                                .map(|x| x.to_owned()).collect();
+                        Ok(Command{
+                            range: (index, index),
+                            command:
+                                if command == 'p' {
+                                    '☃'
+                                }
+                                else if command == 'w' {
+                                    '▤'
+                                }
+                                else if command == 'W' {
+                                    '▥'
+                                }
+                                else {
+                                  command
+                                },
+                            args,
+                        })
+                    }
+                },
+                [begin, end] => {
+                    if the_rest.is_empty() {
+                        Err("No arguments given".to_owned().into())
+                    }
+                    else if the_rest.starts_with("s/") {
+                        Command::substitute((begin, end), the_rest)
+                    }
+                    else if the_rest.starts_with('/') {
+                        Command::search_list((begin, end), the_rest)
+                    }
+                    else if the_rest.starts_with("g/") || the_rest.starts_with("v/") {
+                        Command::global((begin, end), the_rest)
+                    }
+                    else {
+                        let command = the_rest.chars().next().unwrap();
+                        Ok(Command{
+                            range: (begin, end),
+                            command:
+                                if command == 'O' {
+                                    '▣'
+                                }
+                                else if command == 'w' {
+                                    '▤'
+                                }
+                                else if command == 'W' {
+                                    '▥'
+                                }
+                                else {
+                                    command
+                                },
+                            args: the_rest[command.len_utf8()..].split_whitespace()
+                                    .map(|x| x.to_owned()).collect(),
+                        })
+                    }
+                },
+                _ => {
+                    Err(format!("Unable to parse '{}'", line.trim()).into())
                 },
-            };
-
-            // println!("is_range");
-            let caps = caps.unwrap();
-            let begin = number_dot_dollar(state.index, _max_index,
-                    caps.name("begin").unwrap().as_str(), state.prefs.radix);
-            if begin.is_err() {
-                // Why on Earth doesn't this work?
-                // return Err(begin.unwrap());
-                return Err("Can't understand beginning of range.".to_owned());
-            }
-            let begin = begin.unwrap();
-            let end = number_dot_dollar(state.index, _max_index,
-                    caps.name("end").unwrap().as_str(), state.prefs.radix);
-            if end.is_err() {
-                // Why on Earth doesn't this work?
-                // return end;
-                return Err("Can't understand end of range.".to_owned());
             }
-            let end = end.unwrap();
+        }
 
-            let the_rest = caps.name("the_rest").unwrap().as_str().trim();
-            if the_rest.len() == 0 {
-                Err("No arguments given".to_owned())
-            }
-            else {
-                Ok(Command{
-                    range: (begin, end),
-                    command: the_rest.chars().next().unwrap(),
-                    args: the_rest[1..].split_whitespace().map(|x| x.to_owned()).collect(),
-                })
-            }
+        else {
+            Err(format!("Unable to parse '{}'", line.trim()).into())
         }
+    }
+}
 
-        else if is_specified_index {
-            if state.empty() {
-                return Err("Empty file".to_owned());
-            }
 
-            let _max_index = match state.max_index() {
-                Ok(max) => max,
-                Err(error) => {
-                    return Err(format!("? ({})", error));
-                },
-            };
+/// Overwrite the bytes starting at `index` with `new_bytes`, extending the
+/// file if they run past the end.  Returns how many bytes were added.
+fn overwrite(state:&mut State, history:&mut History, index:usize,
+        new_bytes:Vec<u8>) -> Result<usize, Complaint> {
+    let num_old = std::cmp::min(new_bytes.len(), state.all_bytes.len() - index);
+    let num_added = new_bytes.len() - num_old;
+    history.splice(state, index, num_old, new_bytes)?;
+    Ok(num_added)
+}
 
-            // println!("is_specified_index");
-            let caps = caps.unwrap();
-            let specific_index = number_dot_dollar(state.index, _max_index,
-                    caps.name("index").unwrap().as_str(), state.prefs.radix);
-            if specific_index.is_err() {
-                // Why on Earth doesn't this work?
-                // return specific_index;
-                return Err("Can't understand index.".to_owned());
-            }
-            let specific_index = specific_index.unwrap();
-            let the_rest = caps.name("the_rest").unwrap().as_str().trim().to_owned();
-            if the_rest.len() == 0 {
-                Ok(Command{
-                    range: (specific_index, specific_index),
-                    command: 'g',
-                    args: vec![],
-                })
-            }
-            else {
-                let command = the_rest.chars().next().unwrap();
-                let args = the_rest[1..].split_whitespace()
-                        .map(|x| x.to_owned()).collect();
-                Ok(Command{
-                    range: (specific_index, specific_index),
-                    command:
-                        if command == 'p' {
-                            '☃'
-                        }
-                        else {
-                          command
-                        },
-                    args: args,
-                })
-            }
-        }
 
-        else if is_offset_index {
-            // println!("is_specified_index");
-            let caps = caps.unwrap();
-            let as_string = caps.name("offset").unwrap().as_str();
-            let index_offset = usize::from_str_radix(as_string, state.prefs.radix);
-            if index_offset.is_err() {
-                return Err(format!("{} is not a number", as_string));
-            }
-            let index_offset = index_offset.unwrap();
-            let sign = caps.name("sign").unwrap().as_str();
-            let begin = match sign {
-                "+" => state.index + index_offset,
-                "-" => state.index - index_offset,
-                _   => {
-                    return Err(format!("Unknown sign {}", sign));
-                }
-            };
-            let range = (begin, begin);
-            let the_rest = caps.name("the_rest").unwrap().as_str();
-            if the_rest.len() == 0 {
-                Ok(Command{
-                    range: range,
-                    command: 'g',
-                    args: vec![],
-                })
-            }
-            else {
-                Ok(Command{
-                    range: range,
-                    command: the_rest.chars().next().unwrap(),
-                    args: the_rest[1..].split_whitespace().map(|x| x.to_owned()).collect(),
-                })
+/// The needle for the pattern in `args`, or the last one searched for if
+/// there isn't a pattern
+fn needle_from_args(state:&mut State, session:&mut Session, args:&[String])
+        -> Result<Needle, Complaint> {
+    let needle = match args.first() {
+        Some(pattern) => Needle::from_pattern(pattern)?,
+        None => {
+            match search::last_needle(state, session) {
+                Some(needle) => needle,
+                None => {
+                    return Err(Complaint::new(Failure::NotFound,
+                            "No previous search."));
+                },
             }
-        }
+        },
+    };
+    search::remember_needle(state, session, &needle);
+    Ok(needle)
+}
 
-        else {
-            Err(format!("Unable to parse '{}'", line.trim()))
+
+/// Replace occurrences of `needle` lying entirely within `range` with
+/// `replacement`, leaving `state.index` at the last one.  Only replaces the
+/// first occurrence unless `global`.  Returns how many were replaced.
+fn substitute(state:&mut State, history:&mut History, range:(usize, usize),
+        needle:&[u8], replacement:&[u8], global:bool)
+        -> Result<usize, Complaint> {
+    if needle.is_empty() {
+        return Err("Searching for empty string".to_owned().into());
+    }
+
+    let mut num_replaced = 0;
+    let mut begin = range.0;
+
+    /* One past the end of the range, which moves as replacements of a
+     * different length are made */
+    let mut end = range.1 + 1;
+
+    while let Some(offset) = ec::index_of_bytes(needle,
+            &state.all_bytes[begin..end], true) {
+        let index = begin + offset;
+        history.splice(state, index, needle.len(), replacement.to_vec())?;
+        state.index = index;
+        num_replaced += 1;
+        begin = index + replacement.len();
+        end = end - needle.len() + replacement.len();
+        if !global {
+            break;
         }
     }
+
+    if num_replaced == 0 {
+        Err(Complaint::not_found(&ec::string_from_bytes(needle)))
+    }
+    else {
+        Ok(num_replaced)
+    }
 }
 
 
-fn number_dot_dollar(index:usize, _max_index:usize, input:&str, radix:u32)
-        -> Result<usize, String> {
-    match input {
-        "$" => Ok(_max_index),
-        "." => Ok(index),
-        something_else => {
-            if let Ok(number) = usize::from_str_radix(input, radix) {
-                Ok(number)
-            }
-            else {
-                return Err(format!("{} isn't a number in base {}", something_else, radix));
-            }
-        }
+/// Prints what goes under bytes `range.0` - `range.1` once they've been
+/// printed: marks, notes and, if wanted, what the byte at `index` decodes to
+fn print_extras(state:&State, session:&Session, range:(usize, usize),
+        index:usize) {
+    session.print_annotations(state, range);
+    if session.prefs.auto_inspect {
+        inspect::print_inspection(state, index);
+    }
+}
+
+
+/// `pattern` repeated to `len` bytes, cutting off the last repeat if need be.
+/// None if there isn't the memory for them.
+fn repeated(pattern:&[u8], len:usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    if bytes.try_reserve_exact(len).is_err() {
+        return None;
+    }
+    bytes.extend(pattern.iter().cloned().cycle().take(len));
+    Some(bytes)
+}
+
+
+/// `given` if it's a single letter, e.g. the name of a mark or register
+fn one_letter(given:&str) -> Option<char> {
+    let mut chars = given.chars();
+    match (chars.next(), chars.next()) {
+        (Some(letter), None) if letter.is_alphabetic() => Some(letter),
+        _ => None,
+    }
+}
+
+
+/// Moves bytes `range.0` - `range.1` inclusive so they start where byte
+/// `destination` was, as one change.  Returns where they now start.
+fn move_bytes(state:&mut State, history:&mut History, range:(usize, usize),
+        destination:usize) -> Result<usize, Complaint> {
+    if destination > state.all_bytes.len()
+            || (range.0 < destination && destination <= range.1) {
+        return Err(Complaint::new(Failure::BadRange,
+                "Can't move bytes to within themselves or past the end"));
+    }
+
+    let num_moved = range.1 - range.0 + 1;
+    let moved = state.all_bytes[range.0..=range.1].to_vec();
+    history.splice(state, range.0, num_moved, vec![])?;
+    let new_index = if destination > range.1 {
+        destination - num_moved
     }
+    else {
+        destination
+    };
+    history.splice(state, new_index, 0, moved)?;
+    Ok(new_index)
 }
 
 
 /// Returns new index number
-fn minuses(state:&mut State, num_minuses:usize) -> Result<usize, String> {
+fn minuses(state:&mut State, session:&Session, num_minuses:usize)
+        -> Result<usize, String> {
     if state.empty() {
         Err("Empty file".to_owned())
     }
@@ -572,13 +868,14 @@ fn minuses(state:&mut State, num_minuses:usize) -> Result<usize, String> {
     }
     else {
         state.index -= num_minuses;
-        state.print_bytes();
+        render::print_bytes(state, session);
         Ok(state.index)
     }
 }
 
 /// Returns new index number
-fn pluses(state:&mut State, num_pluses:usize) -> Result<usize, String> {
+fn pluses(state:&mut State, session:&Session, num_pluses:usize)
+        -> Result<usize, String> {
     if state.empty() {
         Err("Empty file".to_owned())
     }
@@ -593,7 +890,7 @@ fn pluses(state:&mut State, num_pluses:usize) -> Result<usize, String> {
                 }
                 else {
                     state.index += num_pluses;
-                    state.print_bytes();
+                    render::print_bytes(state, session);
                     Ok(state.index)
                 }
             },
@@ -613,10 +910,10 @@ pub fn cargo_version() -> Result<String, String> {
 }
 
 
-pub fn update_filename(state: &mut ec::State) {
-    let filename = ec::read_string_from_user(Some("Enter new filename: "));
+pub fn update_filename(state: &mut ec::State, input: &mut Input) {
+    let filename = input.read_string("Enter new filename: ");
     if filename.is_err() {
-        println!("? {:?}", filename);
+        complain!(input, Failure::Io, "{:?}", filename);
         return;
     }
     let filename = filename.unwrap();
@@ -628,36 +925,48 @@ pub fn update_filename(state: &mut ec::State) {
 }
 
 
-pub fn load_state_from_file(state: &mut ec::State) {
-    let filename = ec::read_string_from_user(Some(
-                    "Enter filename from which to load state: "));
-    if filename.is_ok() {
-        match State::read_from_filename(&filename.unwrap()) {
-            Ok(new_state) => {
-                *state = new_state;
-            },
-            Err(err) => {
-                println!("? {}", err);
+/// Returns whether a new state was loaded
+pub fn load_state_from_file(state: &mut ec::State, session: &mut Session,
+        input: &mut Input) -> bool {
+    match input.read_string("Enter filename from which to load state: ") {
+        Ok(filename) => {
+            match State::read_from_filename(&filename) {
+                Ok(new_state) => {
+                    *state = new_state;
+                    let backed_up = std::mem::take(&mut session.backed_up);
+                    let bracket_matches = session.bracket_matches;
+                    *session = Session::read_from_filename(&filename)
+                            .unwrap_or_default();
+                    session.backed_up = backed_up;
+                    session.bracket_matches = bracket_matches;
+                    return true;
+                },
+                Err(err) => {
+                    complain!(input, Failure::Io, "{}", err);
+                }
             }
-        }
-    }
-    else {
-        println!("? {:?}", filename);
+        },
+        Err(error) => {
+            complain!(input, Failure::Io, "{:?}", error);
+        },
     }
+
+    false
 }
 
 
-pub fn load_new_file(state: &mut ec::State) {
+/// Returns whether new bytes were loaded
+pub fn load_new_file(state: &mut ec::State, input: &mut Input) -> bool {
 	if state.unsaved_changes {
 		let unsaved_prompt = "You have unsaved changes.  Carry on? (y/n): ";
 		println!("{}", unsaved_prompt);
 		let yeses = vec!["y", "Y", "Yes", "yes"];
 		let nos   = vec!["n", "N", "No",  "no"];
 		let carry_on = loop {
-			let carry_on_s = ec::read_string_from_user(Some(""));
+			let carry_on_s = input.read_string("");
 			if carry_on_s.is_err() {
-				println!("? {:?}", carry_on_s);
-				return;
+				complain!(input, Failure::Io, "{:?}", carry_on_s);
+				return false;
 			}
 			let carry_on_s = carry_on_s.unwrap();
 
@@ -670,15 +979,15 @@ pub fn load_new_file(state: &mut ec::State) {
 			println!("{}", unsaved_prompt);
 		};
 		if !carry_on {
-			return;
+			return false;
 		}
 	}
 
-    let filename = ec::read_string_from_user(Some(
-            "Enter filename from which to load bytes: "));
+    let filename = input.read_string(
+            "Enter filename from which to load bytes: ");
     if filename.is_err() {
-        println!("? {:?}", filename);
-        return;
+        complain!(input, Failure::Io, "{:?}", filename);
+        return false;
     }
     let filename = filename.unwrap();
 
@@ -687,82 +996,152 @@ pub fn load_new_file(state: &mut ec::State) {
     if maybe_all_bytes.is_ok() {
         state.filename = filename;
         state.all_bytes = maybe_all_bytes.unwrap();
-        return;
+        return true;
     }
 
     match maybe_all_bytes {
         Err(ec::AllBytesFromFilenameError::NotARegularFile) => {
-            println!("? {} is not a regular file", filename);
+            complain!(input, Failure::Io, "{} is not a regular file", filename);
         },
         Err(ec::AllBytesFromFilenameError::FileDoesNotExist) => {
-            println!("? {} does not exist", filename);
+            complain!(input, Failure::Io, "{} does not exist", filename);
             println!("Use 'u' to just change filename");
         },
         _ => {
-            println!("? {:?}", maybe_all_bytes);
+            complain!(input, Failure::Io, "{:?}", maybe_all_bytes);
         },
     }
+
+    false
 }
 
 
-pub fn load_prefs(state: &mut ec::State) {
-    let pref_path = ec::preferences_file_path();
-    let filename = ec::read_string_from_user(Some(&format!(
-            "Enter filename from which to load preferences [{}]: ",
-                    pref_path.display())));
-    if filename.is_ok() {
-        let mut filename = filename.unwrap();
-        if filename == "" {
-            if let Some(pref_path_s) = pref_path.to_str() {
-                filename = pref_path_s.to_owned();
-            }
-            else {
-                println!("? Default path ({}) is not valid unicode.",
-                        pref_path.display());
-                return;
-            }
-        }
+pub fn save_state(state: &ec::State, session: &Session, input: &mut Input) {
+    let state_path = ec::state_file_path();
+    let filename = input.read_string(&format!(
+            "Enter filename to save state [{}]: ", state_path.display()));
+    if filename.is_err() {
+        complain!(input, Failure::Io, "{:?}", filename);
+        return;
+    }
+    let filename = filename.unwrap();
 
-        let result = ec::Preferences::read_from_filename(&filename);
-        if result.is_ok() {
-            state.prefs = result.unwrap();
-        }
-        else {
-            println!("? {:?}", result);
+    let path = if filename.is_empty() {
+        if let Some(parent) = state_path.parent() {
+            let _ = std::fs::create_dir_all(parent);
         }
+        state_path
     }
     else {
-        println!("? {:?}", filename);
+        PathBuf::from(filename)
+    };
+
+    if let Err(error) = session.save_with_state(state, &path) {
+        complain!(input, Failure::Io, "{}", error);
     }
 }
 
 
-pub fn write_out(state: &mut ec::State) {
+pub fn save_prefs(state: &ec::State, more_prefs: &MorePreferences,
+        input: &mut Input) {
+    let pref_path = ec::preferences_file_path();
+    let filename = input.read_string(&format!(
+            "Enter filename to save preferences [{}]: ", pref_path.display()));
+    if filename.is_err() {
+        complain!(input, Failure::Io, "{:?}", filename);
+        return;
+    }
+    let filename = filename.unwrap();
+
+    let path = if filename.is_empty() {
+        if let Some(parent) = pref_path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        pref_path
+    }
+    else {
+        PathBuf::from(filename)
+    };
+
+    if let Err(error) = more_prefs.save_with_prefs(&state.prefs, &path) {
+        complain!(input, Failure::Io, "{}", error);
+    }
+}
+
+
+pub fn load_prefs(state: &mut ec::State, more_prefs: &mut MorePreferences,
+        input: &mut Input) {
+    let pref_path = ec::preferences_file_path();
+    let filename = input.read_string(&format!(
+            "Enter filename from which to load preferences [{}]: ",
+                    pref_path.display()));
+    if filename.is_ok() {
+        let mut filename = filename.unwrap();
+        if filename == "" {
+            if let Some(pref_path_s) = pref_path.to_str() {
+                filename = pref_path_s.to_owned();
+            }
+            else {
+                complain!(input, Failure::Io, "Default path ({}) is not valid unicode.",
+                        pref_path.display());
+                return;
+            }
+        }
+
+        let result = ec::Preferences::read_from_filename(&filename);
+        if result.is_ok() {
+            state.prefs = result.unwrap();
+            *more_prefs = MorePreferences::read_from_filename(&filename)
+                    .unwrap_or_default();
+        }
+        else {
+            complain!(input, Failure::Io, "{:?}", result);
+        }
+    }
+    else {
+        complain!(input, Failure::Io, "{:?}", filename);
+    }
+}
+
+
+/// Writes without ever leaving a half-written file, first backing up what
+/// was there according to `backup` unless it's in `backed_up` already.  If
+/// the bytes don't have a filename yet, they're written to `filename` or, if
+/// that's "", one asked for, which becomes theirs once written.
+pub fn write_out(state: &mut ec::State, filename: &str, backup: Backup,
+        backed_up: &mut HashSet<PathBuf>, input: &mut Input) {
     if state.readonly {
-        println!("? Read-only mode");
+        complain!(input, Failure::ReadOnly, "Read-only mode");
         return;
     }
     
     /* Early return if write unsuccessful */
     if state.filename != "" {
-        let result = std::fs::write(&state.filename, &state.all_bytes);
-        if result.is_err() {
-            println!("? (Couldn't write to {})", state.filename);
+        let result = write::write_atomically(Path::new(&state.filename),
+                &state.all_bytes, backup, backed_up);
+        if let Err(error) = result {
+            complain!(input, Failure::Io, "{}", error);
             return;
         }
     }
     else {
-        let filename = ec::read_string_from_user(Some("Enter filename: "));
-        if filename.is_err() {
-            println!("? {:?}", filename);
-            return;
+        let filename = if !filename.is_empty() {
+            filename.to_owned()
         }
-        let filename = filename.unwrap();
+        else {
+            let filename = input.read_string("Enter filename: ");
+            if filename.is_err() {
+                complain!(input, Failure::Io, "{:?}", filename);
+                return;
+            }
+            filename.unwrap()
+        };
 
         /* filename is a string */
-        let result = std::fs::write(&filename, &state.all_bytes);
-        if result.is_err() {
-            println!("? (Couldn't write to given filename '{}')", filename);
+        let result = write::write_atomically(Path::new(&filename),
+                &state.all_bytes, backup, backed_up);
+        if let Err(error) = result {
+            complain!(input, Failure::Io, "{}", error);
             return;
         }
 
@@ -774,9 +1153,32 @@ pub fn write_out(state: &mut ec::State) {
 }
 
 
+/// Writes bytes `range.0` - `range.1` inclusive to `filename`, or adds them to
+/// the end of it if `append`.  What's being edited is left alone, as is the
+/// name it'll be written to by `w`.
+fn write_range(state:&State, range:(usize, usize), filename:&str, append:bool,
+        backup:Backup, backed_up:&mut HashSet<PathBuf>) -> Result<(), String> {
+    let path = Path::new(filename);
+    let mut bytes = vec![];
+    if append && path.exists() {
+        bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                return Err(format!("Couldn't read {}: {}", filename, error));
+            },
+        };
+    }
+    bytes.extend_from_slice(&state.all_bytes[range.0..=range.1]);
+
+    write::write_atomically(path, &bytes, backup, backed_up)
+}
+
+
 /// If `filename` is "", open an empty buffer
+///
+/// Commands queued up in `input` are run before any are read from STDIN.
 pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
-        prefs_path: PathBuf, state_path: PathBuf) -> i32 {
+        prefs_path: PathBuf, state_path: PathBuf, mut input: Input) -> i32 {
     let default_prefs = ec::Preferences {
         show_prompt: !pipe_mode,
         color: color,
@@ -787,6 +1189,12 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
 
     /* Use a state file if one is present */
     let maybe_state = ec::State::read_from_path(&state_path);
+    let mut session = if maybe_state.is_ok() {
+        Session::read_from_path(&state_path).unwrap_or_default()
+    }
+    else {
+        Session::default()
+    };
     let mut state = if maybe_state.is_ok() {
         maybe_state.unwrap()
     }
@@ -809,15 +1217,17 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
                 else {
                     match maybe_all_bytes {
                         Err(ec::AllBytesFromFilenameError::NotARegularFile) => {
-                            println!("{} is not a regular file", filename);
-                            return 1;
+                            input.complain(Failure::Io,
+                                    &format!("{} is not a regular file", filename));
+                            return Failure::Io.exit_status();
                         },
                         Err(ec::AllBytesFromFilenameError::FileDoesNotExist) => {
                             Vec::new()
                         },
                         _ => {
-                            println!("Cannot read {}", filename);
-                            return 1;
+                            input.complain(Failure::Io,
+                                    &format!("Cannot read {}", filename));
+                            return Failure::Io.exit_status();
                         }
                     }
                 }
@@ -829,36 +1239,88 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
     /* Use a config file if one is present */
     if let Ok(prefs) = ec::Preferences::read_from_path(&prefs_path) {
         state.prefs = prefs;
+        session.prefs = MorePreferences::read_from_path(&prefs_path)
+                .unwrap_or_default();
     }
 
+    /* Matches are colored whenever color is on, but brackets would get in the
+     * way of anything reading the bytes printed in pipe mode */
+    session.bracket_matches = !pipe_mode;
+
+    let mut history = History::new(state.unsaved_changes);
+
     if !pipe_mode {
         println!("{}", Color::Yellow.paint("h for help"));
         println!("\n{}", state);
         println!();
-        state.print_bytes();
+        render::print_bytes(&state, &session);
     }
 
+    /* The g/pattern/command being run, if any */
+    let mut global:Option<Global> = None;
+
     // TODO Below here should be a function called main_loop()
     loop {
-        if state.prefs.show_prompt {
-            print!("*");
+        if let Some(exit_status) = input.should_stop() {
+            return exit_status;
         }
-        io::stdout().flush().unwrap();
-        let input = match ec::get_input_or_die() {
-            Ok(input) => input,
-            Err(errcode) => {
-                return errcode;
+
+        /* Stop a g/pattern/command at the first thing that goes wrong */
+        let made_up_line = match global.as_mut() {
+            Some(running) if !input.went_wrong() => {
+                running.next_line(state.all_bytes.len())
+            },
+            _ => None,
+        };
+        if made_up_line.is_none() {
+            if let Some(finished) = global.take() {
+                history.combine_since(finished.num_undos, &finished.line);
+                if input.went_wrong() {
+                    println!("Stopped after running {}", ec::hex_unless_dec_with_radix(
+                            finished.num_run - 1, state.prefs.radix));
+                }
+                else {
+                    println!("Ran {}", ec::hex_unless_dec_with_radix(
+                            finished.num_run, state.prefs.radix));
+                }
             }
+        }
+
+        let line = match made_up_line {
+            Some(line) => input.next_made_up_command(line),
+            None => {
+                if state.prefs.show_prompt && !input.has_pending() {
+                    print!("*");
+                }
+                io::stdout().flush().unwrap();
+                match input.next_command() {
+                    Ok(line) => line,
+                    Err(errcode) => {
+                        return errcode;
+                    }
+                }
+            },
         };
 
-        match Command::from_state_and_line(&mut state, &input) {
+        session.search_wrapped = false;
+        match Command::from_state_and_line(&mut state, &mut session, &line) {
             Ok(command) => {
+                if session.search_wrapped {
+                    /* Keep what's piped out just what was asked for */
+                    if pipe_mode {
+                        eprintln!("Search wrapped");
+                    }
+                    else {
+                        println!("Search wrapped");
+                    }
+                }
+
                 // println!("{:?}", command);
                 match command.command {
 
                     /* Error */
                     'e' => {
-                        println!("?");
+                        complain!(input, Failure::Parse, "Error");
                         continue;
                     },
 
@@ -866,21 +1328,23 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
                     'g' => {
                         match ec::move_to(&mut state, command.range.0) {
                             Ok(_) => {
-                                state.print_bytes();
+                                render::print_bytes(&state, &session);
+                                print_extras(&state, &session, state.range(), state.index);
                             },
                             Err(error) => {
-                                println!("? ({})", error);
+                                complain!(input, Failure::BadRange, "{}", error);
                             }
                         }
                     },
 
                     /* +'s */
                     'G' => {
-                        match pluses(&mut state, command.range.0) {
+                        match pluses(&mut state, &session, command.range.0) {
                             Err(error) => {
-                                println!("? ({})", error);
+                                complain!(input, Failure::BadRange, "{}", error);
                             },
                             Ok(_) => {
+                                print_extras(&state, &session, state.range(), state.index);
                                 continue;
                             }
                         }
@@ -888,11 +1352,12 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
 
                     /* -'s */
                     'H' => {
-                        match minuses(&mut state, command.range.0) {
+                        match minuses(&mut state, &session, command.range.0) {
                             Err(error) => {
-                                println!("? ({})", error);
+                                complain!(input, Failure::BadRange, "{}", error);
                             },
                             Ok(_) => {
+                                print_extras(&state, &session, state.range(), state.index);
                                 continue;
                             }
                         }
@@ -900,29 +1365,23 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
 
                     /* insert */
                     'i' => {
-                        match read_bytes_from_user() {
+                        if command.range.1 > state.all_bytes.len() {
+                            complain!(input, Failure::BadRange, "bad range");
+                            continue;
+                        }
+                        match read_bytes_from_user(&mut input) {
                             Ok(entered_bytes) => {
-                                state.index = command.range.1;
-                                // TODO Find the cheapest way to do this (maybe
-                                // make state.all_bytes a better container)
-                                // TODO Do this with split_off
-                                let mut new = Vec::with_capacity(state.all_bytes.len() + entered_bytes.len());
-                                for i in 0..state.index {
-                                    new.push(state.all_bytes[i]);
-                                }
-                                // TODO Could use Vec::splice here
-                                for i in 0..entered_bytes.len() {
-                                    new.push(entered_bytes[i]);
-                                }
-                                for i in state.index..state.all_bytes.len() {
-                                    new.push(state.all_bytes[i]);
+                                if let Err(error) = history.splice(&mut state,
+                                        command.range.1, 0, entered_bytes) {
+                                    input.complain(error.failure, &error.message);
+                                    continue;
                                 }
-                                state.all_bytes = new;
-                                state.unsaved_changes = true;
-                                state.print_bytes_sans_context(state.range());
+                                state.index = command.range.1;
+                                history.commit(&mut state, &mut session, &line);
+                                render::print_bytes_sans_context(&state, &session, state.range());
                             },
                             Err(error) => {
-                                println!("? ({})", error);
+                                input.complain(error.failure, &error.message);
                             },
                         }
                     },
@@ -935,7 +1394,7 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
                     /* User wants to go up a line */
                     'j' => {
                         if state.empty() {
-                            println!("? (Empty file)");
+                            complain!(input, Failure::BadRange, "Empty file");
                             continue;
                         };
 
@@ -943,7 +1402,8 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
                         let first_byte_to_show_index =
                                 state.index.saturating_sub(width);
                         state.index = first_byte_to_show_index;
-                        state.print_bytes();
+                        render::print_bytes(&state, &session);
+                        print_extras(&state, &session, state.range(), state.index);
                     }
 
 
@@ -951,27 +1411,53 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
                     * character! */
                     'k' => {
                         if state.empty() {
-                            println!("? (Empty file");
+                            complain!(input, Failure::BadRange, "Empty file");
+                            continue;
+                        }
+                        skip_bad_range!(command, state.all_bytes, input);
+                        if let Err(error) = history.splice(&mut state,
+                                command.range.0,
+                                command.range.1 - command.range.0 + 1, vec![]) {
+                            input.complain(error.failure, &error.message);
                             continue;
                         }
-                        skip_bad_range!(command, state.all_bytes);
-                        let mut right_half = state.all_bytes.split_off(command.range.0);
-                        right_half = right_half.split_off(command.range.1 - command.range.0 + 1);
-                        state.all_bytes.append(&mut right_half);
                         state.index = command.range.0;
-                        state.unsaved_changes = true;
-                        state.print_bytes();
+                        history.commit(&mut state, &mut session, &line);
+                        render::print_bytes(&state, &session);
+                        print_extras(&state, &session, state.range(), state.index);
+                    },
+
+
+                    /* Set or print how to back up on write */
+                    'K' => {
+                        if command.args.is_empty() {
+                            println!("{}", session.prefs.backup);
+                            continue;
+                        }
+                        match Backup::from_arg(&command.args[0]) {
+                            Ok(backup) => {
+                                session.prefs.backup = backup;
+                            },
+                            Err(error) => {
+                                complain!(input, Failure::Parse, "{}", error);
+                            },
+                        }
                     },
 
 
                     /* Load new file */
                     'l' => {
-                        load_new_file(&mut state);
+                        if load_new_file(&mut state, &mut input) {
+                            history.clear(state.unsaved_changes);
+                            session.forget_bytes();
+                        }
                     }
 
                     /* Load state from a file */
                     'L' => {
-                        load_state_from_file(&mut state);
+                        if load_state_from_file(&mut state, &mut session, &mut input) {
+                            history.clear(state.unsaved_changes);
+                        }
                     },
 
                     /* Toggle showing char representations of bytes */
@@ -982,6 +1468,58 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
                         }
                     },
 
+                    /* Set, remove or list marks */
+                    'M' => {
+                        if command.args.is_empty() {
+                            session.print_marks(state.prefs.radix);
+                            continue;
+                        }
+
+                        let given = command.args[0].as_str();
+                        let removing = given.starts_with('-');
+                        let name = given.trim_start_matches('-');
+                        let name = match one_letter(name) {
+                            Some(name) => name,
+                            None => {
+                                complain!(input, Failure::Parse,
+                                        "Mark names are one letter, not '{}'",
+                                        name);
+                                continue;
+                            }
+                        };
+
+                        if removing {
+                            if session.marks.remove(&name).is_none() {
+                                complain!(input, Failure::NotFound, "No mark '{}",
+                                        name);
+                            }
+                        }
+                        else {
+                            skip_bad_range!(command, state.all_bytes, input);
+                            session.marks.insert(name, command.range.0);
+                        }
+                    },
+
+                    /* Add, remove or list notes */
+                    'N' => {
+                        if command.args.is_empty() {
+                            session.print_notes(state.prefs.radix);
+                        }
+                        else if command.args.len() == 1 && command.args[0] == "-" {
+                            if session.remove_notes(command.range) == 0 {
+                                complain!(input, Failure::NotFound, "No notes there");
+                            }
+                        }
+                        else {
+                            skip_bad_range!(command, state.all_bytes, input);
+                            session.notes.push(Note {
+                                range: command.range,
+                                text: command.args.join(" "),
+                            });
+                            session.notes.sort_by_key(|note| note.range);
+                        }
+                    },
+
                     /* Toggle showing byte number */
                     'n' => {
                         state.prefs.show_byte_numbers = !state.prefs.show_byte_numbers;
@@ -1035,69 +1573,577 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
                         }
                     },
 
+                    /* Fill a range with a pattern repeated */
+                    '*' => {
+                        skip_bad_range!(command, state.all_bytes, input);
+                        let pattern = match ec::bytes_from_string(
+                                &command.args.join("")) {
+                            Ok(pattern) => pattern,
+                            Err(error) => {
+                                complain!(input, Failure::Parse, "{}", error);
+                                continue;
+                            },
+                        };
+                        if pattern.is_empty() {
+                            complain!(input, Failure::Parse, "No bytes to fill with");
+                            continue;
+                        }
+
+                        let range_len = command.range.1 - command.range.0 + 1;
+                        let bytes = match repeated(&pattern, range_len) {
+                            Some(bytes) => bytes,
+                            None => {
+                                complain!(input, Failure::BadRange,
+                                        "Not enough memory for that many bytes");
+                                continue;
+                            },
+                        };
+                        if let Err(error) = history.splice(&mut state,
+                                command.range.0, range_len, bytes) {
+                            input.complain(error.failure, &error.message);
+                            continue;
+                        }
+                        state.index = command.range.0;
+                        history.commit(&mut state, &mut session, &line);
+                        render::print_bytes_sans_context(&state, &session, command.range);
+                    },
+
+                    /* Toggle searches wrapping around */
+                    'J' => {
+                        session.prefs.wrapscan = !session.prefs.wrapscan;
+                        if !pipe_mode {
+                            println!("{}", session.prefs.wrapscan);
+                        }
+                    },
+
+                    /* Inspect, or toggle inspecting after printing */
+                    '=' => {
+                        if !command.args.is_empty() {
+                            if command.args[0] == "=" {
+                                session.prefs.auto_inspect =
+                                        !session.prefs.auto_inspect;
+                                if !pipe_mode {
+                                    println!("{}", session.prefs.auto_inspect);
+                                }
+                            }
+                            else {
+                                complain!(input, Failure::Parse,
+                                        "Don't understand '={}'", command.args[0]);
+                            }
+                            continue;
+                        }
+
+                        if command.range.0 >= state.all_bytes.len() {
+                            complain!(input, Failure::BadRange, "bad range");
+                            continue;
+                        }
+                        inspect::print_inspection(&state, command.range.0);
+                    },
+
+                    /* Transform a range in place, e.g. ~xor 5a */
+                    '~' => {
+                        skip_bad_range!(command, state.all_bytes, input);
+                        let transform = match Transform::from_args(&command.args,
+                                state.prefs.radix) {
+                            Ok(transform) => transform,
+                            Err(error) => {
+                                complain!(input, Failure::Parse, "{}", error);
+                                continue;
+                            },
+                        };
+                        let transformed = match transform.apply(
+                                &state.all_bytes[command.range.0..=command.range.1]) {
+                            Ok(transformed) => transformed,
+                            Err(error) => {
+                                complain!(input, Failure::BadRange, "{}", error);
+                                continue;
+                            },
+                        };
+
+                        if let Err(error) = history.splice(&mut state,
+                                command.range.0, transformed.len(), transformed) {
+                            input.complain(error.failure, &error.message);
+                            continue;
+                        }
+                        state.index = command.range.0;
+                        history.commit(&mut state, &mut session, &line);
+                        render::print_bytes_sans_context(&state, &session, command.range);
+                    },
+
+                    /* Insert copies of a pattern */
+                    'I' => {
+                        if command.range.0 > state.all_bytes.len() {
+                            complain!(input, Failure::BadRange, "bad range");
+                            continue;
+                        }
+                        if command.args.len() < 2 {
+                            complain!(input, Failure::Parse,
+                                    "Give how many copies and of what, e.g. I 10 00");
+                            continue;
+                        }
+                        let count = match usize::from_str_radix(&command.args[0],
+                                state.prefs.radix) {
+                            Ok(count) => count,
+                            Err(_) => {
+                                complain!(input, Failure::Parse,
+                                        "Can't interpret {} as a number",
+                                        command.args[0]);
+                                continue;
+                            },
+                        };
+                        let pattern = match ec::bytes_from_string(
+                                &command.args[1..].join("")) {
+                            Ok(pattern) => pattern,
+                            Err(error) => {
+                                complain!(input, Failure::Parse, "{}", error);
+                                continue;
+                            },
+                        };
+                        let num_bytes = match pattern.len().checked_mul(count) {
+                            Some(num_bytes) => num_bytes,
+                            None => {
+                                complain!(input, Failure::BadRange,
+                                        "That's too many bytes");
+                                continue;
+                            },
+                        };
+                        if num_bytes == 0 {
+                            complain!(input, Failure::Parse, "Nothing to insert");
+                            continue;
+                        }
+                        let bytes = match repeated(&pattern, num_bytes) {
+                            Some(bytes) if state.all_bytes
+                                    .try_reserve(num_bytes).is_ok() => bytes,
+                            _ => {
+                                complain!(input, Failure::BadRange,
+                                        "Not enough memory for that many bytes");
+                                continue;
+                            },
+                        };
+
+                        if let Err(error) = history.splice(&mut state,
+                                command.range.0, 0, bytes) {
+                            input.complain(error.failure, &error.message);
+                            continue;
+                        }
+                        state.index = command.range.0;
+                        history.commit(&mut state, &mut session, &line);
+                        render::print_bytes_sans_context(&state, &session, state.range());
+                    },
+
+                    /* Move bytes */
+                    'X' => {
+                        skip_bad_range!(command, state.all_bytes, input);
+                        let given = command.args.join(" ");
+                        let destination = match address::parse_addresses(
+                                &mut state, &mut session, &given) {
+                            Ok((addresses, the_rest)) => {
+                                if addresses.len() != 1 || the_rest.trim() != "" {
+                                    complain!(input, Failure::Parse,
+                                            "Give one byte number to move to, not '{}'",
+                                            given);
+                                    continue;
+                                }
+                                addresses[0]
+                            },
+                            Err(error) => {
+                                input.complain(error.failure, &error.message);
+                                continue;
+                            },
+                        };
+
+                        match move_bytes(&mut state, &mut history, command.range,
+                                destination) {
+                            Ok(new_index) => {
+                                state.index = new_index;
+                                history.commit(&mut state, &mut session, &line);
+                                render::print_bytes(&state, &session);
+                                print_extras(&state, &session, state.range(),
+                                        state.index);
+                            },
+                            Err(error) => {
+                                input.complain(error.failure, &error.message);
+                            },
+                        }
+                    },
+
+                    /* Yank bytes into a register, or list registers */
+                    'y' => {
+                        if command.args.is_empty() {
+                            session.print_registers(state.prefs.radix);
+                            continue;
+                        }
+
+                        let given = command.args.join("");
+                        let name = match one_letter(&given) {
+                            Some(name) => name,
+                            None => {
+                                complain!(input, Failure::Parse,
+                                        "Register names are one letter, not '{}'",
+                                        given);
+                                continue;
+                            }
+                        };
+                        skip_bad_range!(command, state.all_bytes, input);
+                        session.registers.insert(name,
+                                state.all_bytes[command.range.0..=command.range.1]
+                                .to_vec());
+                        if !pipe_mode {
+                            println!("Yanked {} byte(s) into {}",
+                                    ec::hex_unless_dec_with_radix(
+                                    command.range.1 - command.range.0 + 1,
+                                    state.prefs.radix), name);
+                        }
+                    },
+
+                    /* Put a register's bytes in, or over what's there if
+                     * the name follows an O */
+                    'Y' => {
+                        let given = command.args.join("");
+                        let (overwriting, name) =
+                                if given.len() > 1 && given.starts_with('O') {
+                            (true, &given[1..])
+                        }
+                        else {
+                            (false, given.as_str())
+                        };
+                        let name = match one_letter(name) {
+                            Some(name) => name,
+                            None => {
+                                complain!(input, Failure::Parse,
+                                        "Register names are one letter, not '{}'",
+                                        name);
+                                continue;
+                            }
+                        };
+                        let bytes = match session.registers.get(&name) {
+                            Some(bytes) => bytes.to_owned(),
+                            None => {
+                                complain!(input, Failure::NotFound,
+                                        "Nothing in register {}", name);
+                                continue;
+                            }
+                        };
+                        if command.range.0 > state.all_bytes.len() {
+                            complain!(input, Failure::BadRange, "bad range");
+                            continue;
+                        }
+
+                        let result = if overwriting {
+                            overwrite(&mut state, &mut history, command.range.0,
+                                    bytes).map(|_| ())
+                        }
+                        else {
+                            history.splice(&mut state, command.range.0, 0, bytes)
+                        };
+                        if let Err(error) = result {
+                            input.complain(error.failure, &error.message);
+                            continue;
+                        }
+                        state.index = command.range.0;
+                        history.commit(&mut state, &mut session, &line);
+                        render::print_bytes_sans_context(&state, &session, state.range());
+                    },
+
                     /* User pressed enter */
                     '\n' => {
                         if state.empty() {
-                            println!("? (Empty file)");
+                            complain!(input, Failure::BadRange, "Empty file");
                             continue;
                         };
 
-                        state.move_index_then_print_bytes();
+                        render::move_index_then_print_bytes(&mut state, &session);
+                        print_extras(&state, &session, state.range(), state.index);
                     }
 
                     /* Print byte(s) at one place, width long */
                     '☃' => {
                         if state.empty() {
-                            println!("? (Empty file)");
+                            complain!(input, Failure::BadRange, "Empty file");
                             continue;
                         };
 
-                        skip_bad_range!(command, state.all_bytes);
+                        skip_bad_range!(command, state.all_bytes, input);
                         state.index = command.range.0;
-                        state.print_bytes_and_move_index();
+                        let range = state.range();
+                        render::print_bytes_and_move_index(&mut state, &session);
+                        print_extras(&state, &session, range, command.range.0);
+                    },
+
+                    /* Overwrite, extending the file if need be */
+                    'O' => {
+                        if command.range.0 > state.all_bytes.len() {
+                            complain!(input, Failure::BadRange, "bad range");
+                            continue;
+                        }
+                        match read_bytes_from_user(&mut input) {
+                            Ok(entered_bytes) => {
+                                let num_added = match overwrite(&mut state,
+                                        &mut history, command.range.0,
+                                        entered_bytes) {
+                                    Ok(num_added) => num_added,
+                                    Err(error) => {
+                                        input.complain(error.failure,
+                                                &error.message);
+                                        continue;
+                                    },
+                                };
+                                state.index = command.range.0;
+                                history.commit(&mut state, &mut session, &line);
+                                if num_added > 0 && !pipe_mode {
+                                    println!("Added {} byte(s) to the end",
+                                            ec::hex_unless_dec_with_radix(
+                                            num_added, state.prefs.radix));
+                                }
+                                render::print_bytes_sans_context(&state, &session, state.range());
+                            },
+                            Err(error) => {
+                                input.complain(error.failure, &error.message);
+                            },
+                        }
+                    },
+
+                    /* Overwrite, but only within range */
+                    '▣' => {
+                        skip_bad_range!(command, state.all_bytes, input);
+                        match read_bytes_from_user(&mut input) {
+                            Ok(entered_bytes) => {
+                                let range_len = command.range.1 - command.range.0 + 1;
+                                if entered_bytes.len() > range_len {
+                                    complain!(input, Failure::BadRange,
+                                            "{} bytes won't fit in {} bytes",
+                                            ec::hex_unless_dec_with_radix(
+                                            entered_bytes.len(), state.prefs.radix),
+                                            ec::hex_unless_dec_with_radix(
+                                            range_len, state.prefs.radix));
+                                    continue;
+                                }
+                                if let Err(error) = overwrite(&mut state,
+                                        &mut history, command.range.0,
+                                        entered_bytes) {
+                                    input.complain(error.failure, &error.message);
+                                    continue;
+                                }
+                                state.index = command.range.0;
+                                history.commit(&mut state, &mut session, &line);
+                                render::print_bytes_sans_context(&state, &session, state.range());
+                            },
+                            Err(error) => {
+                                input.complain(error.failure, &error.message);
+                            },
+                        }
+                    },
+
+                    /* Run a command on every match, or everything between
+                     * matches */
+                    '⊛' | '⊘' => {
+                        if global.is_some() {
+                            complain!(input, Failure::Parse,
+                                    "Can't run g or v from g or v");
+                            continue;
+                        }
+                        skip_bad_range!(command, state.all_bytes, input);
+                        let needle = match needle_from_args(&mut state,
+                                &mut session, &command.args[1..]) {
+                            Ok(needle) => needle,
+                            Err(error) => {
+                                input.complain(error.failure, &error.message);
+                                continue;
+                            },
+                        };
+                        let matches = needle.find_all(
+                                &state.all_bytes[command.range.0..=command.range.1]);
+                        let inverse = command.command == '⊘';
+                        let ranges = global::ranges_of_matches(command.range,
+                                &matches, inverse);
+                        if ranges.is_empty() {
+                            if inverse {
+                                complain!(input, Failure::NotFound,
+                                        "Nothing but {}", needle);
+                            }
+                            else {
+                                complain!(input, Failure::NotFound,
+                                        "{} not found", needle);
+                            }
+                            continue;
+                        }
+
+                        global = Some(Global::new(&line, &command.args[0],
+                                ranges, history.num_undos()));
+                    },
+
+                    /* List or count every match in a range */
+                    '≡' | '№' => {
+                        skip_bad_range!(command, state.all_bytes, input);
+                        let needle = match needle_from_args(&mut state,
+                                &mut session, &command.args) {
+                            Ok(needle) => needle,
+                            Err(error) => {
+                                input.complain(error.failure, &error.message);
+                                continue;
+                            },
+                        };
+                        let matches = needle.find_all(
+                                &state.all_bytes[command.range.0..=command.range.1]);
+
+                        if command.command == '№' {
+                            println!("{}", ec::hex_unless_dec_with_radix(
+                                    matches.len(), state.prefs.radix));
+                            continue;
+                        }
+                        if matches.is_empty() {
+                            complain!(input, Failure::NotFound, "{} not found",
+                                    needle);
+                            continue;
+                        }
+                        /* Each offset, then the rows it's in */
+                        let width = usize::from(state.prefs.width);
+                        for (offset, len) in matches {
+                            let index = command.range.0 + offset;
+                            let last = index + len.max(1) - 1;
+                            println!("{}", ec::hex_unless_dec_with_radix(index,
+                                    state.prefs.radix));
+                            render::print_bytes_sans_context(&state, &session,
+                                    (index - index % width,
+                                    last - last % width + width - 1));
+                        }
+                    },
+
+                    /* Substitute */
+                    '⇄' => {
+                        skip_bad_range!(command, state.all_bytes, input);
+                        let needle = ec::bytes_from_string(&command.args[0]);
+                        let replacement = ec::bytes_from_string(&command.args[1]);
+                        if needle.is_err() || replacement.is_err() {
+                            complain!(input, Failure::Parse,
+                                    "Can't understand bytes to substitute");
+                            continue;
+                        }
+                        let global = command.args.len() > 2;
+                        match substitute(&mut state, &mut history, command.range,
+                                &needle.unwrap(), &replacement.unwrap(), global) {
+                            Ok(num_replaced) => {
+                                history.commit(&mut state, &mut session, &line);
+                                println!("Replaced {}", ec::hex_unless_dec_with_radix(
+                                        num_replaced, state.prefs.radix));
+                                if !state.empty() {
+                                    render::print_bytes(&state, &session);
+                                    print_extras(&state, &session, state.range(),
+                                            state.index);
+                                }
+                            },
+                            Err(error) => {
+                                input.complain(error.failure, &error.message);
+                            },
+                        }
                     },
 
                     /* Print byte(s) with range */
                     'p' => {
                         if state.empty() {
-                            println!("? (Empty file)");
+                            complain!(input, Failure::BadRange, "Empty file");
                             continue;
                         };
 
-                        skip_bad_range!(command, state.all_bytes);
+                        skip_bad_range!(command, state.all_bytes, input);
                         state.index = command.range.0;
                         if let Some(new_index) =
-                                state.print_bytes_sans_context(
+                                render::print_bytes_sans_context(&state, &session, 
                                 (command.range.0, command.range.1)) {
                             state.index = new_index;
+                            print_extras(&state, &session, command.range, command.range.0);
                         }
                         else {
-                            println!("? (no bytes in range {:?})",
+                            complain!(input, Failure::BadRange, "no bytes in range {:?}",
                                     command.range);
                         }
                     },
 
                     /* Save preferences to a file */
                     'P' => {
-                        ec::save_to_path_or_default(&(state.prefs),
-                                "Enter filename to save preferences",
-                                ec::preferences_file_path());
+                        save_prefs(&state, &session.prefs, &mut input);
                     },
 
 
-                    /* Load preferences from a file */
+                    /* Load preferences from a file, or (r)ead a file's bytes in */
                     'r' => {
-                        load_prefs(&mut state);
+                        if command.args.is_empty() {
+                            load_prefs(&mut state, &mut session.prefs, &mut input);
+                            continue;
+                        }
+
+                        if command.range.0 > state.all_bytes.len() {
+                            complain!(input, Failure::BadRange, "bad range");
+                            continue;
+                        }
+                        let overwriting = command.args.len() > 1
+                                && command.args[0] == "O";
+                        let filename = if overwriting {
+                            command.args[1..].join(" ")
+                        }
+                        else {
+                            command.args.join(" ")
+                        };
+
+                        let read_bytes = match ec::all_bytes_from_filename(&filename) {
+                            Ok(read_bytes) => read_bytes,
+                            Err(ec::AllBytesFromFilenameError::NotARegularFile) => {
+                                complain!(input, Failure::Io,
+                                        "{} is not a regular file", filename);
+                                continue;
+                            },
+                            Err(ec::AllBytesFromFilenameError::FileDoesNotExist) => {
+                                complain!(input, Failure::Io, "{} does not exist",
+                                        filename);
+                                continue;
+                            },
+                            Err(error) => {
+                                complain!(input, Failure::Io, "{:?}", error);
+                                continue;
+                            },
+                        };
+                        let num_read = read_bytes.len();
+
+                        let result = if num_read == 0 {
+                            Ok(0)
+                        }
+                        else if overwriting {
+                            overwrite(&mut state, &mut history, command.range.0,
+                                    read_bytes)
+                        }
+                        else {
+                            history.splice(&mut state, command.range.0, 0,
+                                    read_bytes).map(|_| num_read)
+                        };
+                        let num_added = match result {
+                            Ok(num_added) => num_added,
+                            Err(error) => {
+                                input.complain(error.failure, &error.message);
+                                continue;
+                            },
+                        };
+                        state.index = command.range.0;
+                        history.commit(&mut state, &mut session, &line);
+
+                        println!("Read {} byte(s), adding {}",
+                                ec::hex_unless_dec_with_radix(num_read,
+                                state.prefs.radix),
+                                ec::hex_unless_dec_with_radix(num_added,
+                                state.prefs.radix));
+                        if !state.empty() {
+                            render::print_bytes_sans_context(&state, &session, state.range());
+                        }
                     },
 
                     /* Print byte(s) at *current* place, width long */
                     'Q' => {
                         if state.empty() {
-                            println!("? (Empty file)");
+                            complain!(input, Failure::BadRange, "Empty file");
                             continue;
                         };
 
-                        state.print_bytes();
+                        render::print_bytes(&state, &session);
+                        print_extras(&state, &session, state.range(), state.index);
                     },
 
                     /* Quit */
@@ -1112,14 +2158,17 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
 
                     /* Write state to a file */
                     'S' => {
-                        ec::save_to_path_or_default(&state,
-                                "Enter filename to save state",
-                                ec::state_file_path());
+                        save_state(&state, &session, &mut input);
                     },
 
                     /* Print state */
                     's' => {
                         println!("{}", state);
+                        println!("Backup on write: {}", session.prefs.backup);
+                        println!("Inspect after printing: {}",
+                                session.prefs.auto_inspect);
+                        println!("Searches wrap around: {}",
+                                session.prefs.wrapscan);
                     },
 
                     /* Change after_context */
@@ -1134,12 +2183,74 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
 
                     /* (u)pdate iflename */
                     'u' => {
-                        update_filename(&mut state);
+                        update_filename(&mut state, &mut input);
+                        if state.unsaved_changes {
+                            history.forget_saved();
+                        }
                     },
 
                     /* Write out */
                     'w' => {
-                        write_out(&mut state);
+                        let filename = command.args.join(" ");
+                        if filename.is_empty() || filename == state.filename
+                                || state.filename.is_empty() {
+                            write_out(&mut state, &filename,
+                                    session.prefs.backup,
+                                    &mut session.backed_up, &mut input);
+                        }
+                        else {
+                            /* Just a copy, so the bytes are still unsaved */
+                            if let Err(error) = write::write_atomically(
+                                    Path::new(&filename), &state.all_bytes,
+                                    session.prefs.backup,
+                                    &mut session.backed_up) {
+                                complain!(input, Failure::Io, "{}", error);
+                            }
+                            continue;
+                        }
+                        if !state.unsaved_changes {
+                            history.mark_saved();
+                        }
+                    },
+
+                    /* Write or append a range to a file */
+                    '▤' | '▥' => {
+                        skip_bad_range!(command, state.all_bytes, input);
+                        let filename = command.args.join(" ");
+                        if filename.is_empty() {
+                            complain!(input, Failure::Parse,
+                                    "No filename given to write to");
+                            continue;
+                        }
+                        let append = command.command == '▥';
+                        let own_file = filename == state.filename;
+                        if own_file && state.readonly {
+                            complain!(input, Failure::ReadOnly, "Read-only mode");
+                            continue;
+                        }
+                        match write_range(&state, command.range, &filename,
+                                append, session.prefs.backup,
+                                &mut session.backed_up) {
+                            Ok(_) => {
+                                if own_file {
+                                    /* What's on disk is no longer what was
+                                     * written */
+                                    history.forget_saved();
+                                    state.unsaved_changes = true;
+                                }
+                                if !pipe_mode {
+                                    println!("{} {} byte(s) to {}",
+                                            if append {"Appended"} else {"Wrote"},
+                                            ec::hex_unless_dec_with_radix(
+                                            command.range.1 - command.range.0 + 1,
+                                            state.prefs.radix),
+                                            filename);
+                                }
+                            },
+                            Err(error) => {
+                                complain!(input, Failure::Io, "{}", error);
+                            },
+                        }
                     },
 
                     /* Change width */
@@ -1149,15 +2260,65 @@ pub fn actual_runtime(filename:&str, pipe_mode:bool, color:bool, readonly:bool,
                         }
                     },
 
+                    /* Undo, or print history */
+                    'z' => {
+                        if !command.args.is_empty() {
+                            if command.args[0] == "h" {
+                                history.print(state.prefs.radix);
+                            }
+                            else {
+                                complain!(input, Failure::Parse, "Don't understand 'z{}'",
+                                        command.args[0]);
+                            }
+                            continue;
+                        }
+
+                        match history.undo(&mut state, &mut session) {
+                            Ok(undone) => {
+                                if !pipe_mode {
+                                    println!("Undid '{}'", undone);
+                                }
+                                if !state.empty() {
+                                    render::print_bytes(&state, &session);
+                                    print_extras(&state, &session, state.range(),
+                                            state.index);
+                                }
+                            },
+                            Err(error) => {
+                                input.complain(error.failure, &error.message);
+                            },
+                        }
+                    },
+
+                    /* Redo */
+                    'Z' => {
+                        match history.redo(&mut state, &mut session) {
+                            Ok(redone) => {
+                                if !pipe_mode {
+                                    println!("Redid '{}'", redone);
+                                }
+                                if !state.empty() {
+                                    render::print_bytes(&state, &session);
+                                    print_extras(&state, &session, state.range(),
+                                            state.index);
+                                }
+                            },
+                            Err(error) => {
+                                input.complain(error.failure, &error.message);
+                            },
+                        }
+                    },
+
                     /* Catchall error */
                     _ => {
-                        println!("? (Don't understand command '{}')", command.command);
+                        complain!(input, Failure::Parse, "Don't understand command '{}'",
+                                command.command);
                         continue;
                     },
                 }
             },
             Err(error) => {
-                println!("? ({})", error);
+                input.complain(error.failure, &error.message);
                 continue;
             }
         }
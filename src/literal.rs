@@ -0,0 +1,301 @@
+/// Bytes from what's typed at the `>` prompt.  Hex bytes can be mixed freely
+/// with
+///
+///   - typed numbers like `u32le:1234`, `i16be:-5`, `u8:0xff` or `f64be:3.14`.
+///     Integers are decimal unless they start with `0x`.  `f32:` and `f64:`
+///     are little-endian.
+///   - `"quoted text"`, as UTF-8
+///   - `u"quoted text"`, as little-endian UTF-16
+///   - C escapes like `\x00\n`, in or out of quotes, and `\/` and `\?`
+pub fn bytes_from_input(line:&str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut rest = line.trim_start();
+
+    while !rest.is_empty() {
+        if rest.starts_with('"') {
+            let (pieces, after) = quoted(&rest[1..])?;
+            for piece in pieces {
+                piece.push_utf8(&mut bytes);
+            }
+            rest = after;
+        }
+        else if rest.starts_with("u\"") {
+            let (pieces, after) = quoted(&rest[2..])?;
+            for piece in pieces {
+                piece.push_utf16(&mut bytes);
+            }
+            rest = after;
+        }
+        else if rest.starts_with('\\') {
+            let (piece, after) = escape(&rest[1..])?;
+            piece.push_utf8(&mut bytes);
+            rest = after;
+        }
+        else {
+            let end = rest.find(|c:char| c.is_whitespace() || c == '"'
+                    || c == '\\').unwrap_or(rest.len());
+            let word = &rest[..end];
+            match word.find(':') {
+                Some(colon) => {
+                    bytes.extend(typed(&word[..colon], &word[(colon + 1)..])?);
+                },
+                None => {
+                    bytes.extend(ec::bytes_from_string(word)?);
+                },
+            }
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(bytes)
+}
+
+
+/// A character of quoted text or what an escape stands for
+enum Piece {
+    Char(char),
+
+    /// From `\x`, which means this byte whatever the encoding
+    Byte(u8),
+}
+
+
+impl Piece {
+    fn push_utf8(&self, bytes:&mut Vec<u8>) {
+        match self {
+            Piece::Char(c) => {
+                let mut buffer = [0; 4];
+                bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+            },
+            Piece::Byte(byte) => {
+                bytes.push(*byte);
+            },
+        }
+    }
+
+
+    /// Little-endian.  A byte becomes a whole code unit, so `\x41` is the
+    /// same as `A`.
+    fn push_utf16(&self, bytes:&mut Vec<u8>) {
+        match self {
+            Piece::Char(c) => {
+                let mut buffer = [0; 2];
+                for unit in c.encode_utf16(&mut buffer) {
+                    bytes.extend(&unit.to_le_bytes());
+                }
+            },
+            Piece::Byte(byte) => {
+                bytes.extend(&u16::from(*byte).to_le_bytes());
+            },
+        }
+    }
+}
+
+
+/// The text up to the closing `"`, with escapes replaced, and what's after
+/// the `"`
+fn quoted(input:&str) -> Result<(Vec<Piece>, &str), String> {
+    let mut pieces = vec![];
+    let mut rest = input;
+    loop {
+        let mut chars = rest.chars();
+        match chars.next() {
+            Some('"') => {
+                return Ok((pieces, chars.as_str()));
+            },
+            Some('\\') => {
+                let (piece, after) = escape(chars.as_str())?;
+                pieces.push(piece);
+                rest = after;
+            },
+            Some(c) => {
+                pieces.push(Piece::Char(c));
+                rest = chars.as_str();
+            },
+            None => {
+                return Err(format!("No closing \" in \"{}", input));
+            },
+        }
+    }
+}
+
+
+/// What the escape after a `\` stands for and what's after it
+fn escape(input:&str) -> Result<(Piece, &str), String> {
+    let mut chars = input.chars();
+    let c = match chars.next() {
+        Some('n') => '\n',
+        Some('r') => '\r',
+        Some('t') => '\t',
+        Some('0') => '\0',
+        Some('\\') => '\\',
+        Some('"') => '"',
+
+        /* So searches can have their delimiters in them */
+        Some('/') => '/',
+        Some('?') => '?',
+        Some('x') => {
+            let digits = chars.as_str();
+            if digits.len() < 2 || !digits.is_char_boundary(2) {
+                return Err(format!("\\x needs two hex digits, not '{}'", digits));
+            }
+            return match u8::from_str_radix(&digits[..2], 16) {
+                Ok(byte) => Ok((Piece::Byte(byte), &digits[2..])),
+                Err(_) => {
+                    Err(format!("\\x needs two hex digits, not '{}'",
+                            &digits[..2]))
+                },
+            };
+        },
+        Some(c) => {
+            return Err(format!("Don't know the escape \\{}", c));
+        },
+        None => {
+            return Err("Nothing after \\".to_owned());
+        },
+    };
+    Ok((Piece::Char(c), chars.as_str()))
+}
+
+
+/// The bytes of e.g. `u32le` `1234`
+fn typed(kind:&str, value:&str) -> Result<Vec<u8>, String> {
+    let big_endian = kind.ends_with("be");
+    let base = match kind.strip_suffix("le") {
+        Some(base) => base,
+        None => kind.strip_suffix("be").unwrap_or(kind),
+    };
+
+    match base {
+        "f32" => {
+            let float:f32 = match value.parse() {
+                Ok(float) => float,
+                Err(_) => {
+                    return Err(format!("Can't interpret '{}' as an f32", value));
+                },
+            };
+            return Ok(if big_endian {
+                float.to_be_bytes().to_vec()
+            }
+            else {
+                float.to_le_bytes().to_vec()
+            });
+        },
+        "f64" => {
+            let float:f64 = match value.parse() {
+                Ok(float) => float,
+                Err(_) => {
+                    return Err(format!("Can't interpret '{}' as an f64", value));
+                },
+            };
+            return Ok(if big_endian {
+                float.to_be_bytes().to_vec()
+            }
+            else {
+                float.to_le_bytes().to_vec()
+            });
+        },
+        _ => {},
+    }
+
+    let (signed, size) = match base {
+        "u8" => (false, 1),
+        "i8" => (true, 1),
+        "u16" => (false, 2),
+        "i16" => (true, 2),
+        "u32" => (false, 4),
+        "i32" => (true, 4),
+        "u64" => (false, 8),
+        "i64" => (true, 8),
+        _ => {
+            return Err(format!("Don't know the type '{}'", kind));
+        },
+    };
+    if size > 1 && base == kind {
+        return Err(format!("Say which end first, e.g. {}le or {}be", kind, kind));
+    }
+
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let magnitude = match digits.strip_prefix("0x") {
+        Some(hex) => i128::from_str_radix(hex, 16),
+        None => digits.parse::<i128>(),
+    };
+    let number = match magnitude {
+        Ok(magnitude) => if negative {-magnitude} else {magnitude},
+        Err(_) => {
+            return Err(format!("Can't interpret '{}' as a number", value));
+        },
+    };
+
+    let bits = 8 * size as u32;
+    let (min, max) = if signed {
+        (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+    }
+    else {
+        (0, (1i128 << bits) - 1)
+    };
+    if number < min || number > max {
+        return Err(format!("{} doesn't fit in {}", value, base));
+    }
+
+    let le_bytes = (number as u64).to_le_bytes();
+    let mut bytes = le_bytes[..size].to_vec();
+    if big_endian {
+        bytes.reverse();
+    }
+    Ok(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_range() {
+        assert_eq!(typed("u8", "255"), Ok(vec![0xff]));
+        assert!(typed("u8", "256").is_err());
+        assert!(typed("u8", "-1").is_err());
+        assert_eq!(typed("i8", "-128"), Ok(vec![0x80]));
+        assert!(typed("i8", "128").is_err());
+        assert!(typed("i8", "-129").is_err());
+        assert_eq!(typed("u16le", "0xffff"), Ok(vec![0xff, 0xff]));
+        assert!(typed("u16le", "0x10000").is_err());
+        assert_eq!(typed("i32be", "-1"), Ok(vec![0xff, 0xff, 0xff, 0xff]));
+        assert!(typed("i32le", "2147483648").is_err());
+        assert_eq!(typed("u64be", "18446744073709551615"), Ok(vec![0xff; 8]));
+        assert!(typed("u64be", "18446744073709551616").is_err());
+        assert_eq!(typed("i64le", "-9223372036854775808"),
+                Ok(vec![0, 0, 0, 0, 0, 0, 0, 0x80]));
+        assert!(typed("i64le", "-9223372036854775809").is_err());
+    }
+
+    #[test]
+    fn test_typed_endianness() {
+        assert_eq!(typed("u16le", "0x1234"), Ok(vec![0x34, 0x12]));
+        assert_eq!(typed("u16be", "0x1234"), Ok(vec![0x12, 0x34]));
+        assert_eq!(typed("u32le", "0x12345678"), Ok(vec![0x78, 0x56, 0x34, 0x12]));
+        assert_eq!(typed("u32be", "0x12345678"), Ok(vec![0x12, 0x34, 0x56, 0x78]));
+        assert_eq!(typed("i16be", "-2"), Ok(vec![0xff, 0xfe]));
+        assert_eq!(typed("f32", "1"), Ok(vec![0x00, 0x00, 0x80, 0x3f]));
+        assert_eq!(typed("f32be", "1"), Ok(vec![0x3f, 0x80, 0x00, 0x00]));
+        assert_eq!(typed("f64le", "2"), Ok(vec![0, 0, 0, 0, 0, 0, 0, 0x40]));
+        assert!(typed("u32", "1").is_err());
+        assert!(typed("u24le", "1").is_err());
+    }
+
+    #[test]
+    fn test_bytes_from_input() {
+        assert_eq!(bytes_from_input("de ad"), Ok(vec![0xde, 0xad]));
+        assert_eq!(bytes_from_input("\"AB\" 00 u16be:1"),
+                Ok(vec![0x41, 0x42, 0x00, 0x00, 0x01]));
+        assert_eq!(bytes_from_input("u\"A\\x42\""),
+                Ok(vec![0x41, 0x00, 0x42, 0x00]));
+        assert_eq!(bytes_from_input("\\x00\\n\\/"), Ok(vec![0x00, 0x0a, 0x2f]));
+        assert!(bytes_from_input("\"unclosed").is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::session;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+
+/// What to keep of a file's old contents when writing over it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Backup {
+    #[default]
+    Off,
+
+    /// `file~`
+    Tilde,
+
+    /// `file.1700000000~`, seconds since the epoch
+    Timestamped,
+}
+
+
+impl Backup {
+    pub fn from_arg(arg:&str) -> Result<Backup, String> {
+        match arg {
+            "off" => Ok(Backup::Off),
+            "~" => Ok(Backup::Tilde),
+            "time" => Ok(Backup::Timestamped),
+            _ => Err(format!("Backup can be off, ~ or time, not '{}'", arg)),
+        }
+    }
+}
+
+
+impl fmt::Display for Backup {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backup::Off => write!(f, "off"),
+            Backup::Tilde => write!(f, "~"),
+            Backup::Timestamped => write!(f, "time"),
+        }
+    }
+}
+
+
+/// Preferences edhex_core's `Preferences` doesn't have.  Saved in the same
+/// file, whose fields it doesn't share.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MorePreferences {
+    pub backup: Backup,
+
+    /// Print what the current bytes decode to whenever they're printed
+    pub auto_inspect: bool,
+
+    /// Searches that reach one end of the bytes carry on from the other
+    pub wrapscan: bool,
+}
+
+
+impl MorePreferences {
+    pub fn read_from_filename(filename:&str) -> Result<MorePreferences, String> {
+        MorePreferences::read_from_path(Path::new(filename))
+    }
+
+
+    pub fn read_from_path(path:&Path) -> Result<MorePreferences, String> {
+        session::read_json(path)
+    }
+
+
+    /// Writes `prefs` and `self` to `path` as one file, which
+    /// `Preferences::read_from_path` can still read
+    pub fn save_with_prefs(&self, prefs:&ec::Preferences, path:&Path)
+            -> Result<(), String> {
+        session::save_merged(prefs, self, path)
+    }
+}
@@ -0,0 +1,276 @@
+use ansi_term::{Color, Style};
+use crate::search;
+use crate::session::Session;
+use ec::{Byte, State};
+use std::cmp::min;
+
+
+/// How far either side of the bytes in a row to look for regex matches that
+/// reach into it, since there's no telling how long a regex match can be
+const REGEX_REACH:usize = 0x100;
+
+
+/// How a byte is shown for what was last searched for
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Highlight {
+    Plain,
+    Match,
+
+    /// Part of the match starting at the current index
+    Current,
+}
+
+
+/// Like `State::print_bytes`, but with where the last search matches
+/// highlighted.  Returns the index of the last byte
+/// in the main line.
+pub fn print_bytes(state:&State, session:&Session) -> Option<usize> {
+    let max_index = state.max_index().ok()?;
+    let (main_line, last_index) = line_with_break(state, session, state.index,
+            max_index, state.prefs.underline_main_line)?;
+
+    /* Before context, cut short where the main line starts */
+    if state.index > 0 {
+        let mut from = state.index.saturating_sub(
+                state.prefs.before_context * usize::from(state.prefs.width));
+        while let Some((line, last)) = line_with_break(state, session, from,
+                state.index - 1, false) {
+            println!("{}", line);
+            from = last + 1;
+        }
+    }
+
+    println!("{}", main_line);
+
+    let mut from = last_index + 1;
+    for _ in 0..state.prefs.after_context {
+        match line_with_break(state, session, from, max_index, false) {
+            Some((line, last)) => {
+                println!("{}", line);
+                from = last + 1;
+            },
+            None => {
+                break;
+            },
+        }
+    }
+
+    Some(last_index)
+}
+
+
+/// Like `State::print_bytes_sans_context`, but with where the last search
+/// matches highlighted.  Returns the index of the
+/// first byte on the last line.
+pub fn print_bytes_sans_context(state:&State, session:&Session,
+        range:(usize, usize)) -> Option<usize> {
+    let range = state.byte_indices_between(range)?;
+    let mut from = range.0;
+    let mut last_from = range.0;
+    while let Some((line, last)) = line_with_break(state, session, from,
+            range.1, false) {
+        println!("{}", line);
+        last_from = from;
+        from = last + 1;
+    }
+    Some(last_from)
+}
+
+
+/// Like `State::print_bytes_and_move_index`
+pub fn print_bytes_and_move_index(state:&mut State, session:&Session) {
+    match print_bytes(state, session) {
+        Some(last_index) => {
+            match state.max_index() {
+                Ok(max) => {
+                    state.index = min(last_index + 1, max);
+                },
+                Err(_) => {
+                    println!("? (No bytes)");
+                },
+            }
+        },
+        None => {
+            println!("? (unknown error)");
+        },
+    }
+}
+
+
+/// Like `State::move_index_then_print_bytes`
+pub fn move_index_then_print_bytes(state:&mut State, session:&Session) {
+    match state.index_of_next_line() {
+        Some(next_index) => {
+            state.index = next_index;
+            print_bytes(state, session);
+        },
+        None => {
+            println!("? No bytes after current line");
+        },
+    }
+}
+
+
+/// Like `State::line_with_break`: the row of bytes from `begin` up to `end`, a
+/// break or a row's worth, whichever comes first, and the index of the last
+/// byte in it.  Without color, matches are bracketed, `[de ad]`, and the
+/// current match `{de ad}`, the brackets taking the place of the spaces and
+/// `|`s around them.
+fn line_with_break(state:&State, session:&Session, begin:usize, end:usize,
+        underline:bool) -> Option<(String, usize)> {
+    if end < begin || begin >= state.all_bytes.len() {
+        return None;
+    }
+
+    let width = usize::from(state.prefs.width);
+    let mut last = begin;
+    while last < min(end, state.all_bytes.len() - 1) && last + 1 - begin < width
+            && !state.breaks.contains(&(last + 1)) {
+        last += 1;
+    }
+
+    let highlights = highlights(state, session, (begin, last));
+    let highlight = |index:usize| highlights[index - begin];
+    let color = state.prefs.color;
+    let between = |before, after| if color {None} else {bracket(before, after)};
+    let closing = between(highlight(last), Highlight::Plain);
+
+    let mut line = String::new();
+    let opening = between(Highlight::Plain, highlight(begin));
+    if state.prefs.show_byte_numbers {
+        line += &ec::address_display(begin, state.prefs.radix,
+                &state.prefs.n_padding, underline);
+        line.push(opening.unwrap_or('|'));
+    }
+    else if let Some(opening) = opening {
+        line.push(opening);
+    }
+
+    for index in begin..=last {
+        if index > begin {
+            line.push(between(highlight(index - 1), highlight(index))
+                    .unwrap_or(' '));
+        }
+        let byte = state.all_bytes[index];
+        line += &styled(ec::padded_byte(byte), byte, highlight(index), color,
+                underline);
+    }
+
+    /* Pad for unprinted bytes, the closing bracket going in the first space
+     * or, if there isn't one, in place of the | before the chars */
+    let mut padding = "   ".repeat(width.saturating_sub(last + 1 - begin));
+    let mut separator = '|';
+    if let Some(closing) = closing {
+        if padding.is_empty() {
+            if state.prefs.show_chars {
+                separator = closing;
+            }
+            else {
+                line.push(closing);
+            }
+        }
+        else {
+            padding.replace_range(..1, &closing.to_string());
+        }
+    }
+    line += &padding;
+
+    if state.prefs.show_chars {
+        line.push(separator);
+        line += "   ";
+
+        let mut before = Highlight::Plain;
+        for index in begin..=last {
+            if let Some(bracket) = between(before, highlight(index)) {
+                line.push(bracket);
+            }
+            let byte = state.all_bytes[index];
+            line += &styled(Byte(byte).as_char().to_string(), byte,
+                    highlight(index), color, underline);
+            before = highlight(index);
+        }
+        if let Some(closing) = closing {
+            line.push(closing);
+        }
+    }
+
+    Some((line, last))
+}
+
+
+/// How each of bytes `range.0` - `range.1` is highlighted.  All plain without
+/// color unless `session.bracket_matches`.
+fn highlights(state:&State, session:&Session, range:(usize, usize))
+        -> Vec<Highlight> {
+    let mut highlights = vec![Highlight::Plain; range.1 - range.0 + 1];
+    if !state.prefs.color && !session.bracket_matches {
+        return highlights;
+    }
+    let needle = match search::last_needle(state, session) {
+        Some(needle) => needle,
+        None => {
+            return highlights;
+        },
+    };
+
+    /* Matches can start before the row and end after it */
+    let reach = match needle.max_len() {
+        Some(max_len) => max_len.saturating_sub(1),
+        None => REGEX_REACH,
+    };
+    let from = range.0.saturating_sub(reach);
+    let to = min(range.1.saturating_add(reach), state.all_bytes.len() - 1);
+
+    for (offset, len) in needle.find_all(&state.all_bytes[from..=to]) {
+        let begin = from + offset;
+        let end = begin + len - 1;
+        let highlight = if begin == state.index {
+            Highlight::Current
+        }
+        else {
+            Highlight::Match
+        };
+        if end < range.0 || begin > range.1 {
+            continue;
+        }
+        for index in std::cmp::max(begin, range.0)..=min(end, range.1) {
+            if highlights[index - range.0] != Highlight::Current {
+                highlights[index - range.0] = highlight;
+            }
+        }
+    }
+
+    highlights
+}
+
+
+/// What goes between bytes highlighted `before` and `after` without color, if
+/// anything
+fn bracket(before:Highlight, after:Highlight) -> Option<char> {
+    match (before, after) {
+        _ if before == after => None,
+        (_, Highlight::Current) => Some('{'),
+        (Highlight::Current, _) => Some('}'),
+        (_, Highlight::Match) => Some('['),
+        (Highlight::Match, _) => Some(']'),
+        _ => None,
+    }
+}
+
+
+/// `text` showing `byte` in its color, reversed if it's part of a match or
+/// black on yellow if it's part of the current one
+fn styled(text:String, byte:u8, highlight:Highlight, color:bool,
+        underline:bool) -> String {
+    if !color {
+        return text;
+    }
+
+    let style = match highlight {
+        Highlight::Plain => Byte(byte).color().normal(),
+        Highlight::Match => Byte(byte).color().reverse(),
+        Highlight::Current => Color::Black.on(Color::Yellow).bold(),
+    };
+    let style:Style = if underline {style.underline()} else {style};
+    style.paint(text).to_string()
+}
@@ -0,0 +1,634 @@
+use crate::literal;
+use crate::session::Session;
+use ec::State;
+use regex::bytes::Regex;
+use std::fmt;
+
+
+/// What a pattern like `/deadbeef` or `/"GET /"` searches for
+#[derive(Clone, Debug)]
+pub enum Needle {
+    Bytes(Vec<u8>),
+
+    /// Bytes whose ASCII letters match either case
+    IgnoringCase(Vec<u8>),
+
+    /// Bytes with wildcards and jumps, e.g. `de ?? be ef`, along with the
+    /// regex they're searched for with
+    Masked(Vec<Element>, Regex),
+
+    /// Compiled with `REGEX_FLAGS` in front
+    Regex(Regex),
+}
+
+
+/// Part of a masked needle
+#[derive(Clone, Debug, PartialEq)]
+pub enum Element {
+    /// A byte matches if its bits in `mask` are those of `value`, so `4?` is
+    /// `Byte {value: 0x40, mask: 0xf0}`
+    Byte {value: u8, mask: u8},
+
+    /// Anywhere from `min` to `max` bytes, e.g. `[2-4]`
+    Jump {min: usize, max: usize},
+}
+
+
+/// Makes `.` match any byte, newlines included, and `\xff` the byte ff rather
+/// than the UTF-8 for ÿ
+const REGEX_FLAGS:&str = "(?s-u)";
+
+/// Most bytes a `[min-max]` jump can skip.  Masked needles are searched for
+/// as regexes, which take time in proportion to the haystack times the size
+/// of the regex however many jumps there are, but each jump adds `max` states
+/// to the regex, which would otherwise grow past what the regex crate builds.
+const MAX_JUMP:usize = 0x1000;
+
+/// Longest match of a regex `find_all` looks for starting inside another.
+/// Finding where every match starting inside a match of, say, `r:00+` over a
+/// long run of zeros ends would take time in proportion to the run's length
+/// squared.
+const MAX_OVERLAP:usize = 0x100;
+
+
+impl Needle {
+    /// `pattern` is what's between the delimiters.  It can be
+    ///
+    ///   - hex like `deadbeef`
+    ///   - `"quoted text"`, optionally mixed with hex and anything else typed
+    ///     at the `>` prompt, e.g. `"MZ" 90 00` or `u"wide"`
+    ///   - `t:text` for UTF-8, `u:text` for UTF-16LE or `U:text` for
+    ///     UTF-16BE, taking the rest of the pattern as is
+    ///   - hex with `?` or `.` for any nibble and `[n]` or `[min-max]` for
+    ///     jumps over that many (decimal) bytes of anything, e.g.
+    ///     `de ?? be [2-4] 4?`.  `.` is handy searching backward, where `?`
+    ///     would end the pattern unless written `\?`.
+    ///   - `r:regex`, a regular expression over the raw bytes, e.g.
+    ///     `r:\x7fELF.{12}\x02\x00`, in which `\/` is `/`
+    ///
+    /// Text ignores case with `ti:`, `ui:` or `Ui:`, or with an `i` after the
+    /// closing quote.
+    pub fn from_pattern(pattern:&str) -> Result<Needle, String> {
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err("Searching for empty string".to_owned());
+        }
+
+        let (prefix, text) = match pattern.find(':') {
+            Some(colon) => (&pattern[..colon], &pattern[(colon + 1)..]),
+            None => ("", pattern),
+        };
+        let needle = match prefix {
+            "t" => Needle::Bytes(text.as_bytes().to_vec()),
+            "ti" => Needle::IgnoringCase(text.as_bytes().to_vec()),
+            "u" => Needle::Bytes(utf16(text, false)),
+            "ui" => Needle::IgnoringCase(utf16(text, false)),
+            "U" => Needle::Bytes(utf16(text, true)),
+            "Ui" => Needle::IgnoringCase(utf16(text, true)),
+            "r" => {
+                match Regex::new(&format!("{}{}", REGEX_FLAGS,
+                        text.replace("\\/", "/"))) {
+                    Ok(regex) => Needle::Regex(regex),
+                    Err(error) => {
+                        return Err(format!("Bad regex: {}", error));
+                    },
+                }
+            },
+            _ => {
+                if is_masked(pattern) {
+                    let elements = masked(pattern)?;
+                    let regex = masked_regex(&elements)?;
+                    Needle::Masked(elements, regex)
+                }
+                else if pattern.starts_with('"') && pattern.ends_with("\"i") {
+                    Needle::IgnoringCase(literal::bytes_from_input(
+                            &pattern[..(pattern.len() - 1)])?)
+                }
+                else {
+                    Needle::Bytes(literal::bytes_from_input(pattern)?)
+                }
+            },
+        };
+
+        if needle.is_empty() {
+            return Err("Searching for empty string".to_owned());
+        }
+        Ok(needle)
+    }
+
+
+    /// Whether there's not even one byte to match
+    fn is_empty(&self) -> bool {
+        match self {
+            Needle::Bytes(bytes) | Needle::IgnoringCase(bytes) => {
+                bytes.len() == 0
+            },
+            Needle::Masked(elements, _) => {
+                !elements.iter().any(|element|
+                        matches!(element, Element::Byte {..}))
+            },
+
+            /* Matching nothing is ignored when searching */
+            Needle::Regex(_) => false,
+        }
+    }
+
+
+    /// Exactly the bytes searched for, if there's only one thing this can
+    /// match, e.g. to keep in `state.last_search`
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Needle::Bytes(bytes) => Some(bytes),
+            Needle::IgnoringCase(_) | Needle::Masked(..) | Needle::Regex(_) => None,
+        }
+    }
+
+
+    /// The most bytes a match can be, if there's any telling
+    pub fn max_len(&self) -> Option<usize> {
+        match self {
+            Needle::Bytes(bytes) | Needle::IgnoringCase(bytes) => Some(bytes.len()),
+            Needle::Masked(elements, _) => {
+                Some(elements.iter().map(|element| match element {
+                    Element::Byte {..} => 1,
+                    Element::Jump {max, ..} => *max,
+                }).sum())
+            },
+            Needle::Regex(_) => None,
+        }
+    }
+
+
+    /// The offset and length of the first match in `haystack`, or the last
+    /// if not `forward`
+    pub fn find(&self, haystack:&[u8], forward:bool) -> Option<(usize, usize)> {
+        match self {
+            Needle::Bytes(bytes) => {
+                ec::index_of_bytes(bytes, haystack, forward)
+                        .map(|offset| (offset, bytes.len()))
+            },
+            Needle::IgnoringCase(bytes) => {
+                if bytes.len() > haystack.len() {
+                    return None;
+                }
+                let matches = |offset:&usize|
+                        haystack[*offset..(*offset + bytes.len())]
+                        .eq_ignore_ascii_case(bytes);
+                let mut offsets = 0..=(haystack.len() - bytes.len());
+                let offset = if forward {
+                    offsets.find(matches)
+                }
+                else {
+                    offsets.rev().find(matches)
+                };
+                offset.map(|offset| (offset, bytes.len()))
+            },
+            Needle::Masked(_, regex) | Needle::Regex(regex) => {
+                if forward {
+                    regex.find_iter(haystack).find(|found| found.end() > found.start())
+                            .map(|found| (found.start(), found.end() - found.start()))
+                }
+                else {
+                    last_regex_match(regex, haystack)
+                }
+            },
+        }
+    }
+
+
+    /// The offset and length of every match in `haystack`, overlapping ones
+    /// included, as searching again with `/` would find them.  For regexes,
+    /// which can match any number of bytes, matches starting inside others are
+    /// only found up to `MAX_OVERLAP` bytes long.
+    pub fn find_all(&self, haystack:&[u8]) -> Vec<(usize, usize)> {
+        let regex = match self {
+            Needle::Regex(regex) => regex,
+            _ => {
+                return self.find_all_of_max_len(haystack);
+            },
+        };
+
+        let mut matches = vec![];
+        for found in regex.find_iter(haystack) {
+            if found.end() == found.start() {
+                continue;
+            }
+            matches.push((found.start(), found.end() - found.start()));
+
+            for offset in (found.start() + 1)..found.end() {
+                let window = &haystack[offset..
+                        std::cmp::min(offset + MAX_OVERLAP, haystack.len())];
+                if let Some(inside) = regex.find(window) {
+                    if inside.start() == 0 && inside.end() > 0 {
+                        matches.push((offset, inside.end()));
+                    }
+                }
+            }
+        }
+        matches
+    }
+
+
+    /// `find_all` for needles with a `max_len`, whose matches can't run on
+    /// past it
+    fn find_all_of_max_len(&self, haystack:&[u8]) -> Vec<(usize, usize)> {
+        let mut matches = vec![];
+        let mut start = 0;
+        while start < haystack.len() {
+            match self.find(&haystack[start..], true) {
+                Some((offset, len)) => {
+                    matches.push((start + offset, len));
+                    start += offset + 1;
+                },
+                None => {
+                    break;
+                },
+            }
+        }
+        matches
+    }
+}
+
+/// The offset and length of the match starting last in `haystack`, ignoring
+/// matches of nothing
+fn last_regex_match(regex:&Regex, haystack:&[u8]) -> Option<(usize, usize)> {
+    let last = regex.find_iter(haystack)
+            .filter(|found| found.end() > found.start()).last()?;
+
+    /* Matches don't overlap, so one could start inside the last */
+    for offset in ((last.start() + 1)..last.end()).rev() {
+        if let Some(found) = regex.find_at(haystack, offset) {
+            if found.start() == offset && found.end() > found.start() {
+                return Some((found.start(), found.end() - found.start()));
+            }
+        }
+    }
+    Some((last.start(), last.end() - last.start()))
+}
+
+
+impl fmt::Display for Needle {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Needle::Bytes(bytes) => {
+                write!(f, "{}", ec::string_from_bytes(bytes))
+            },
+            Needle::IgnoringCase(bytes) => {
+                write!(f, "{} ignoring case", ec::string_from_bytes(bytes))
+            },
+            Needle::Masked(elements, _) => {
+                let words:Vec<String> = elements.iter().map(|element|
+                        match element {
+                            Element::Byte {value, mask} => {
+                                format!("{}{}", nibble(value >> 4, mask >> 4),
+                                        nibble(value & 0xf, mask & 0xf))
+                            },
+                            Element::Jump {min, max} if min == max => {
+                                format!("[{}]", min)
+                            },
+                            Element::Jump {min, max} => {
+                                format!("[{}-{}]", min, max)
+                            },
+                        }).collect();
+                write!(f, "{}", words.join(" "))
+            },
+            Needle::Regex(regex) => {
+                write!(f, "r:{}", &regex.as_str()[REGEX_FLAGS.len()..])
+            },
+        }
+    }
+}
+
+
+/// e.g. `a` or `?` for a masked-out nibble
+fn nibble(value:u8, mask:u8) -> char {
+    if mask == 0 {
+        '?'
+    }
+    else {
+        std::char::from_digit(u32::from(value), 16).unwrap()
+    }
+}
+
+
+/// Whether `pattern` is hex with wildcards or jumps rather than anything else
+/// `literal::bytes_from_input` takes
+fn is_masked(pattern:&str) -> bool {
+    let pattern = pattern.replace("\\?", "?");
+    pattern.contains(&['?', '.', '['][..])
+            && pattern.chars().all(|c| c.is_ascii_hexdigit() || c.is_whitespace()
+                    || "?.[]-".contains(c))
+}
+
+
+/// The elements of e.g. `de ?? be [2-4] 4?`
+fn masked(pattern:&str) -> Result<Vec<Element>, String> {
+    let pattern = pattern.replace("\\?", "?");
+    let mut elements = vec![];
+    let mut nibbles = vec![];
+    let mut rest = pattern.as_str();
+
+    loop {
+        let mut chars = rest.chars();
+        let c = chars.next();
+        match c {
+            Some(c) if c.is_ascii_hexdigit() || c == '?' || c == '.' => {
+                nibbles.push(c);
+                rest = chars.as_str();
+                continue;
+            },
+            _ => {},
+        }
+
+        /* A run of nibbles just ended */
+        if nibbles.len() % 2 != 0 {
+            return Err(format!("Odd number of hex digits in '{}'",
+                    nibbles.iter().collect::<String>()));
+        }
+        for pair in nibbles.chunks(2) {
+            let (high, high_mask) = nibble_and_mask(pair[0]);
+            let (low, low_mask) = nibble_and_mask(pair[1]);
+            elements.push(Element::Byte {
+                value: (high << 4) | low,
+                mask: (high_mask << 4) | low_mask,
+            });
+        }
+        nibbles.clear();
+
+        match c {
+            None => {
+                break;
+            },
+            Some('[') => {
+                let (jump, after) = match chars.as_str().find(']') {
+                    Some(close) => {
+                        (&chars.as_str()[..close], &chars.as_str()[(close + 1)..])
+                    },
+                    None => {
+                        return Err(format!("No closing ] in '{}'", rest));
+                    },
+                };
+                elements.push(jump_from_string(jump)?);
+                rest = after;
+            },
+            Some(c) if c.is_whitespace() => {
+                rest = chars.as_str();
+            },
+            Some(c) => {
+                return Err(format!("Didn't expect '{}' in '{}'", c, pattern));
+            },
+        }
+    }
+
+    Ok(elements)
+}
+
+
+/// A nibble's value and which of its bits must match
+fn nibble_and_mask(c:char) -> (u8, u8) {
+    match c.to_digit(16) {
+        Some(digit) => (digit as u8, 0xf),
+        None => (0, 0),
+    }
+}
+
+
+/// e.g. `3` or `2-4` from between the brackets of a jump
+fn jump_from_string(jump:&str) -> Result<Element, String> {
+    let number = |s:&str| match s.trim().parse::<usize>() {
+        Ok(number) if number <= MAX_JUMP => Ok(number),
+        _ => Err(format!("Jumps are [n] or [min-max] with decimal numbers up \
+                to {}, not [{}]", MAX_JUMP, jump)),
+    };
+    let (min, max) = match jump.find('-') {
+        Some(dash) => (number(&jump[..dash])?, number(&jump[(dash + 1)..])?),
+        None => (number(jump)?, number(jump)?),
+    };
+    if min > max {
+        return Err(format!("[{}] jumps backward", jump));
+    }
+    Ok(Element::Jump {min, max})
+}
+
+
+/// A regex matching what `elements` do, trying the shortest jumps first
+fn masked_regex(elements:&[Element]) -> Result<Regex, String> {
+    let mut regex = REGEX_FLAGS.to_owned();
+    for element in elements {
+        match element {
+            Element::Byte {value, mask: 0xff} => {
+                regex.push_str(&format!("\\x{:02x}", value));
+            },
+            Element::Byte {mask: 0, ..} => {
+                regex.push('.');
+            },
+            Element::Byte {value, mask} => {
+                regex.push('[');
+                for byte in 0..=0xff_u8 {
+                    if byte & mask == *value {
+                        regex.push_str(&format!("\\x{:02x}", byte));
+                    }
+                }
+                regex.push(']');
+            },
+            Element::Jump {min, max} => {
+                regex.push_str(&format!(".{{{},{}}}?", min, max));
+            },
+        }
+    }
+
+    match Regex::new(&regex) {
+        Ok(regex) => Ok(regex),
+        Err(error) => Err(format!("Pattern is too complicated: {}", error)),
+    }
+}
+
+
+fn utf16(text:&str, big_endian:bool) -> Vec<u8> {
+    text.encode_utf16().flat_map(|unit|
+            if big_endian {unit.to_be_bytes()} else {unit.to_le_bytes()})
+            .collect()
+}
+
+
+/// Regex matching a pattern up to `delimiter`, which can still appear in
+/// quotes or escaped with `\`, for building the regexes commands are parsed
+/// with
+pub fn pattern_regex(delimiter:char) -> String {
+    format!(r#"(?:"(?:[^"\\]|\\.)*"|\\.|[^{}"\\])+"#,
+            regex::escape(&delimiter.to_string()))
+}
+
+
+/// Splits `input` at the first `delimiter` not in quotes or escaped with `\`
+/// into the pattern before and whatever's after.  If there's no such
+/// `delimiter`, the pattern is all of `input`.
+pub fn split_pattern(input:&str, delimiter:char) -> (&str, &str) {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+        }
+        else if c == '\\' {
+            escaped = true;
+        }
+        else if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        else if c == delimiter && !in_quotes {
+            return (&input[..i], &input[(i + c.len_utf8())..]);
+        }
+    }
+    (input, "")
+}
+
+
+/// Where `needle` first occurs in `all_bytes` from `from` on, or last occurs
+/// before `from` if not `forward`.  If it doesn't and `session.prefs.wrapscan`,
+/// searches all the bytes from the other end, setting
+/// `session.search_wrapped` if that finds it.  Returns the index and the
+/// length of the match.
+pub fn find_wrapping(needle:&Needle, all_bytes:&[u8], from:usize, forward:bool,
+        session:&mut Session) -> Option<(usize, usize)> {
+    let from = std::cmp::min(from, all_bytes.len());
+    let found = if forward {
+        needle.find(&all_bytes[from..], true)
+                .map(|(offset, len)| (from + offset, len))
+    }
+    else {
+        needle.find(&all_bytes[..from], false)
+    };
+
+    match found {
+        Some(found) => Some(found),
+        None if session.prefs.wrapscan => {
+            let found = needle.find(all_bytes, forward);
+            session.search_wrapped = found.is_some();
+            found
+        },
+        None => None,
+    }
+}
+
+
+/// What `/` or an empty pattern searches for again
+pub fn last_needle(state:&State, session:&Session) -> Option<Needle> {
+    match &session.last_needle {
+        Some(needle) => Some(needle.clone()),
+        None => state.last_search.clone().map(Needle::Bytes),
+    }
+}
+
+
+/// Remember `needle` to search for again.  If it's just bytes, they're kept
+/// in `state.last_search` too.
+pub fn remember_needle(state:&mut State, session:&mut Session, needle:&Needle) {
+    if let Some(bytes) = needle.bytes() {
+        state.last_search = Some(bytes.to_vec());
+    }
+    session.last_needle = Some(needle.clone());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_bytes() {
+        let haystack = [0xde, 0xad, 0x00, 0xde, 0xad, 0x00];
+        let needle = Needle::from_pattern("dead").unwrap();
+        assert_eq!(needle.find(&haystack, true), Some((0, 2)));
+        assert_eq!(needle.find(&haystack, false), Some((3, 2)));
+        assert_eq!(needle.find(&haystack[4..], true), None);
+    }
+
+    #[test]
+    fn test_find_ignoring_case() {
+        let haystack = b"Hello hELLo";
+        let needle = Needle::from_pattern("\"hello\"i").unwrap();
+        assert_eq!(needle.find(haystack, true), Some((0, 5)));
+        assert_eq!(needle.find(haystack, false), Some((6, 5)));
+        assert_eq!(needle.find(b"hell", true), None);
+    }
+
+    #[test]
+    fn test_masked() {
+        assert_eq!(masked("de ?? 4."), Ok(vec![
+                Element::Byte {value: 0xde, mask: 0xff},
+                Element::Byte {value: 0x00, mask: 0x00},
+                Element::Byte {value: 0x40, mask: 0xf0},
+        ]));
+        assert_eq!(masked("de[2-4]\\?f [3]"), Ok(vec![
+                Element::Byte {value: 0xde, mask: 0xff},
+                Element::Jump {min: 2, max: 4},
+                Element::Byte {value: 0x0f, mask: 0x0f},
+                Element::Jump {min: 3, max: 3},
+        ]));
+        assert!(masked("de ?").is_err());
+        assert!(masked("de [4-2]").is_err());
+        assert!(masked("de [2").is_err());
+        assert!(masked(&format!("de [{}]", MAX_JUMP + 1)).is_err());
+    }
+
+    #[test]
+    fn test_find_masked() {
+        let haystack = [0xde, 0x41, 0x00, 0x00, 0x42, 0xde, 0x43, 0x44];
+        let needle = Needle::from_pattern("de 4? [0-3] 4?").unwrap();
+        assert_eq!(needle.find(&haystack, true), Some((0, 5)));
+        assert_eq!(needle.find(&haystack, false), Some((5, 3)));
+
+        /* The shortest jump wins */
+        let needle = Needle::from_pattern("de [0-4] 4?").unwrap();
+        assert_eq!(needle.find(&haystack, true), Some((0, 2)));
+
+        let needle = Needle::from_pattern("de [3] 4?").unwrap();
+        assert_eq!(needle.find(&haystack, true), Some((0, 5)));
+        assert_eq!(needle.find(&haystack[1..], true), None);
+    }
+
+    #[test]
+    fn test_find_regex() {
+        let haystack = b"a1 b22 c333";
+        let needle = Needle::from_pattern("r:[a-z][0-9]+").unwrap();
+        assert_eq!(needle.find(haystack, true), Some((0, 2)));
+        assert_eq!(needle.find(haystack, false), Some((7, 4)));
+
+        /* Matching nothing doesn't count */
+        let needle = Needle::from_pattern("r:x*").unwrap();
+        assert_eq!(needle.find(haystack, true), None);
+    }
+
+    #[test]
+    fn test_find_all_overlapping() {
+        let needle = Needle::from_pattern("aa").unwrap();
+        assert_eq!(needle.find_all(&[0xaa, 0xaa, 0xaa, 0x00, 0xaa]),
+                vec![(0, 1), (1, 1), (2, 1), (4, 1)]);
+        let needle = Needle::from_pattern("aaaa").unwrap();
+        assert_eq!(needle.find_all(&[0xaa, 0xaa, 0xaa]), vec![(0, 2), (1, 2)]);
+    }
+
+    #[test]
+    fn test_find_all_regex() {
+        let needle = Needle::from_pattern("r:\\x00+").unwrap();
+        assert_eq!(needle.find_all(&[0x00, 0x00, 0x00, 0x01, 0x00]),
+                vec![(0, 3), (1, 2), (2, 1), (4, 1)]);
+
+        /* Matches inside others only run so far */
+        let zeros = vec![0x00; MAX_OVERLAP + 2];
+        let matches = needle.find_all(&zeros);
+        assert_eq!(matches.len(), zeros.len());
+        assert_eq!(matches[0], (0, MAX_OVERLAP + 2));
+        assert_eq!(matches[1], (1, MAX_OVERLAP));
+        assert_eq!(matches[3], (3, MAX_OVERLAP - 1));
+    }
+
+    #[test]
+    fn test_max_len() {
+        assert_eq!(Needle::from_pattern("dead").unwrap().max_len(), Some(2));
+        assert_eq!(Needle::from_pattern("de [2-5] ad").unwrap().max_len(),
+                Some(7));
+        assert_eq!(Needle::from_pattern("r:de+").unwrap().max_len(), None);
+    }
+}
@@ -0,0 +1,401 @@
+use ansi_term::Color;
+use crate::preferences::{Backup, MorePreferences};
+use crate::search::Needle;
+use crate::write;
+use ec::State;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+
+/// Free text attached to bytes `range.0` - `range.1` inclusive
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Note {
+    pub range: (usize, usize),
+    pub text: String,
+}
+
+
+/// The marks and notes one splice of the bytes killed or cut short, so undoing
+/// it can put them back
+#[derive(Debug, Default)]
+pub struct Displaced {
+    /// Marks on killed bytes, where they were
+    marks: Vec<(char, usize)>,
+
+    /// Notes on killed bytes as they were and what's left of them, if
+    /// anything
+    notes: Vec<(Note, Option<Note>)>,
+}
+
+
+/// What edhex keeps track of beyond edhex_core's `State`.  Saved in the same
+/// file as the `State`, whose fields it doesn't share.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    /// Byte numbers by name, e.g. `'a`
+    pub marks: BTreeMap<char, usize>,
+    pub notes: Vec<Note>,
+
+    /// Bytes yanked by name, e.g. `ya`
+    pub registers: BTreeMap<char, Vec<u8>>,
+
+    /// Saved with the state just as edhex_core's `Preferences` are
+    #[serde(flatten)]
+    pub prefs: MorePreferences,
+
+    /// What was last searched for, which `state.last_search` can only hold
+    /// if it's just bytes
+    #[serde(skip)]
+    pub last_needle: Option<Needle>,
+
+    /// Bracket where what was last searched for occurs in the bytes printed
+    /// when there's no color to highlight it with
+    #[serde(skip)]
+    pub bracket_matches: bool,
+
+    /// Whether a search made by the command being parsed wrapped around
+    #[serde(skip)]
+    pub search_wrapped: bool,
+
+    /// Files already backed up to `file~` by writing over them
+    #[serde(skip)]
+    pub backed_up: HashSet<PathBuf>,
+}
+
+
+impl Session {
+    /// Reads what's saved alongside a `State` in `filename`
+    pub fn read_from_filename(filename:&str) -> Result<Session, String> {
+        Session::read_from_path(Path::new(filename))
+    }
+
+
+    pub fn read_from_path(path:&Path) -> Result<Session, String> {
+        read_json(path)
+    }
+
+
+    /// Writes `state` and `self` to `path` as one file, which
+    /// `State::read_from_path` can still read
+    pub fn save_with_state(&self, state:&State, path:&Path) -> Result<(), String> {
+        save_merged(&ec::StateSansBytes::from(state), self, path)
+    }
+
+
+    /// Forget the marks, notes and registers, e.g. after loading another file
+    /// whose bytes they'd be taken to be about
+    pub fn forget_bytes(&mut self) {
+        self.marks.clear();
+        self.notes.clear();
+        self.registers.clear();
+    }
+
+
+    pub fn print_marks(&self, radix:u32) {
+        if self.marks.is_empty() {
+            println!("No marks");
+        }
+        for (name, index) in &self.marks {
+            println!("'{}  {}", name, ec::hex_unless_dec_with_radix(*index, radix));
+        }
+    }
+
+
+    /// Prints each register's size and first few bytes
+    pub fn print_registers(&self, radix:u32) {
+        if self.registers.is_empty() {
+            println!("No registers");
+        }
+        for (name, bytes) in &self.registers {
+            let shown = std::cmp::min(bytes.len(), 8);
+            println!("{}  {} byte(s)  {}{}", name,
+                    ec::hex_unless_dec_with_radix(bytes.len(), radix),
+                    ec::string_from_bytes(&bytes[..shown]),
+                    if shown < bytes.len() {" ..."} else {""});
+        }
+    }
+
+
+    pub fn print_notes(&self, radix:u32) {
+        if self.notes.is_empty() {
+            println!("No notes");
+        }
+        for note in &self.notes {
+            println!("{}", note_line(note, radix));
+        }
+    }
+
+
+    /// Prints the marks and notes touching bytes `range.0` - `range.1`, e.g.
+    /// under the bytes `state.print_bytes` just printed
+    pub fn print_annotations(&self, state:&State, range:(usize, usize)) {
+        let mut lines = vec![];
+        for (name, index) in &self.marks {
+            if range.0 <= *index && *index <= range.1 {
+                lines.push(format!("'{} is at {}", name,
+                        ec::hex_unless_dec_with_radix(*index, state.prefs.radix)));
+            }
+        }
+        for note in &self.notes {
+            if note.range.0 <= range.1 && range.0 <= note.range.1 {
+                lines.push(note_line(note, state.prefs.radix));
+            }
+        }
+
+        for line in lines {
+            if state.prefs.color {
+                println!("{}", Color::Cyan.paint(line));
+            }
+            else {
+                println!("{}", line);
+            }
+        }
+    }
+
+
+    /// Returns how many notes were removed
+    pub fn remove_notes(&mut self, range:(usize, usize)) -> usize {
+        let num_notes = self.notes.len();
+        self.notes.retain(|note|
+                note.range.1 < range.0 || range.1 < note.range.0);
+        num_notes - self.notes.len()
+    }
+
+
+    /// Keeps marks and notes on the same bytes after `num_old` bytes at
+    /// `index` are replaced by `num_new` bytes.  Marks on bytes that no
+    /// longer exist are removed and notes are cut down to the bytes left.
+    pub fn splice(&mut self, index:usize, num_old:usize, num_new:usize)
+            -> Displaced {
+        let mut displaced = Displaced::default();
+
+        let mut marks = BTreeMap::new();
+        for (name, mark) in &self.marks {
+            match moved(*mark, index, num_old, num_new) {
+                Some(mark) => {
+                    marks.insert(*name, mark);
+                },
+                None => {
+                    displaced.marks.push((*name, *mark));
+                },
+            }
+        }
+        self.marks = marks;
+
+        let mut notes = vec![];
+        for note in self.notes.drain(..) {
+            let begin = moved(note.range.0, index, num_old, num_new);
+            let end = moved(note.range.1, index, num_old, num_new);
+            if let (Some(begin), Some(end)) = (begin, end) {
+                notes.push(Note {
+                    range: (begin, end),
+                    ..note
+                });
+                continue;
+            }
+
+            /* What's left is between the first and last bytes not killed */
+            let begin = begin.unwrap_or(index + num_new);
+            let end = match end {
+                Some(end) => Some(end),
+                None => (index + num_new).checked_sub(1),
+            };
+            let left = match end {
+                Some(end) if begin <= end => {
+                    Some(Note {
+                        range: (begin, end),
+                        text: note.text.to_owned(),
+                    })
+                },
+                _ => None,
+            };
+            if let Some(left) = &left {
+                notes.push(left.clone());
+            }
+            displaced.notes.push((note, left));
+        }
+        self.notes = notes;
+
+        displaced
+    }
+
+
+    /// Undoes `splice`, given what it returned
+    pub fn unsplice(&mut self, index:usize, num_old:usize, num_new:usize,
+            displaced:&Displaced) {
+        for (_, left) in &displaced.notes {
+            if let Some(left) = left {
+                if let Some(i) = self.notes.iter().position(|note| note == left) {
+                    self.notes.remove(i);
+                }
+            }
+        }
+
+        self.splice(index, num_new, num_old);
+        for (name, mark) in &displaced.marks {
+            self.marks.insert(*name, *mark);
+        }
+        for (note, _) in &displaced.notes {
+            self.notes.push(note.clone());
+        }
+        self.notes.sort_by_key(|note| note.range);
+    }
+}
+
+
+/// Where the byte at `old_index` is after `num_old` bytes at `index` are
+/// replaced by `num_new` bytes, or None if it was killed
+fn moved(old_index:usize, index:usize, num_old:usize, num_new:usize)
+        -> Option<usize> {
+    if old_index < index {
+        Some(old_index)
+    }
+    else if old_index >= index + num_old {
+        Some(old_index - num_old + num_new)
+    }
+    else if old_index - index < num_new {
+        Some(old_index)
+    }
+    else {
+        None
+    }
+}
+
+
+fn note_line(note:&Note, radix:u32) -> String {
+    if note.range.0 == note.range.1 {
+        format!("{}  {}", ec::hex_unless_dec_with_radix(note.range.0, radix),
+                note.text)
+    }
+    else {
+        format!("{}-{}  {}", ec::hex_unless_dec_with_radix(note.range.0, radix),
+                ec::hex_unless_dec_with_radix(note.range.1, radix), note.text)
+    }
+}
+
+
+pub fn read_json<T:DeserializeOwned>(path:&Path) -> Result<T, String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            return Err(format!("Couldn't read {}: {}", path.display(), error));
+        },
+    };
+
+    match serde_json::from_str(&contents) {
+        Ok(read) => Ok(read),
+        Err(error) => {
+            Err(format!("Couldn't parse {}: {}", path.display(), error))
+        },
+    }
+}
+
+
+/// Writes the fields of `first` and `second` to `path` as one JSON object.
+/// Where they share a field, `second`'s wins.
+pub fn save_merged<A:Serialize, B:Serialize>(first:&A, second:&B, path:&Path)
+        -> Result<(), String> {
+    let mut combined = match serde_json::to_value(first) {
+        Ok(combined) => combined,
+        Err(error) => {
+            return Err(format!("Couldn't serialize: {}", error));
+        },
+    };
+    let second = match serde_json::to_value(second) {
+        Ok(second) => second,
+        Err(error) => {
+            return Err(format!("Couldn't serialize: {}", error));
+        },
+    };
+    if let (Some(combined), Some(second)) =
+            (combined.as_object_mut(), second.as_object()) {
+        for (key, value) in second {
+            combined.insert(key.to_owned(), value.to_owned());
+        }
+    }
+
+    let serialized = match serde_json::to_string_pretty(&combined) {
+        Ok(serialized) => serialized,
+        Err(error) => {
+            return Err(format!("Couldn't serialize: {}", error));
+        },
+    };
+    write::write_atomically(path, serialized.as_bytes(), Backup::Off,
+            &mut HashSet::new())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(begin:usize, end:usize, text:&str) -> Note {
+        Note {
+            range: (begin, end),
+            text: text.to_owned(),
+        }
+    }
+
+    fn session() -> Session {
+        let mut session = Session::default();
+        session.marks.insert('a', 1);
+        session.marks.insert('b', 4);
+        session.marks.insert('c', 8);
+        session.notes.push(note(0, 2, "header"));
+        session.notes.push(note(3, 5, "length"));
+        session.notes.push(note(8, 9, "checksum"));
+        session
+    }
+
+    #[test]
+    fn test_moved() {
+        assert_eq!(moved(1, 3, 2, 5), Some(1));
+        assert_eq!(moved(5, 3, 2, 5), Some(8));
+        assert_eq!(moved(4, 3, 2, 5), Some(4));
+        assert_eq!(moved(4, 3, 2, 1), None);
+        assert_eq!(moved(3, 3, 0, 1), Some(4));
+    }
+
+    #[test]
+    fn test_splice_insert() {
+        let mut session = session();
+        session.splice(4, 0, 2);
+        assert_eq!(session.marks.get(&'a'), Some(&1));
+        assert_eq!(session.marks.get(&'b'), Some(&6));
+        assert_eq!(session.marks.get(&'c'), Some(&10));
+        assert_eq!(session.notes, vec![note(0, 2, "header"),
+                note(3, 7, "length"), note(10, 11, "checksum")]);
+    }
+
+    #[test]
+    fn test_splice_kill() {
+        let mut session = session();
+        let displaced = session.splice(4, 3, 0);
+        assert_eq!(session.marks.get(&'b'), None);
+        assert_eq!(session.marks.get(&'c'), Some(&5));
+        assert_eq!(session.notes, vec![note(0, 2, "header"),
+                note(3, 3, "length"), note(5, 6, "checksum")]);
+
+        session.unsplice(4, 3, 0, &displaced);
+        assert_eq!(session.marks, self::session().marks);
+        assert_eq!(session.notes, self::session().notes);
+    }
+
+    #[test]
+    fn test_splice_kill_whole_note() {
+        let mut session = session();
+        let displaced = session.splice(2, 5, 1);
+        assert_eq!(session.marks.get(&'a'), Some(&1));
+        assert_eq!(session.marks.get(&'b'), None);
+        assert_eq!(session.marks.get(&'c'), Some(&4));
+        assert_eq!(session.notes, vec![note(0, 2, "header"),
+                note(4, 5, "checksum")]);
+
+        session.unsplice(2, 5, 1, &displaced);
+        assert_eq!(session.marks, self::session().marks);
+        assert_eq!(session.notes, self::session().notes);
+    }
+}
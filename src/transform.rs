@@ -0,0 +1,283 @@
+/// How many bytes arithmetic is done on at once and in what order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Word {
+    pub size: usize,
+    pub big_endian: bool,
+}
+
+
+impl Word {
+    const BYTE: Word = Word {size: 1, big_endian: false};
+
+
+    /// `""` for single bytes or e.g. `16le`, `32be`, `64le`
+    fn from_suffix(suffix:&str) -> Option<Word> {
+        if suffix.is_empty() {
+            return Some(Word::BYTE);
+        }
+
+        let big_endian = if suffix.ends_with("le") {
+            false
+        }
+        else if suffix.ends_with("be") {
+            true
+        }
+        else {
+            return None;
+        };
+        let size = match &suffix[..(suffix.len() - 2)] {
+            "16" => 2,
+            "32" => 4,
+            "64" => 8,
+            _ => {
+                return None;
+            },
+        };
+
+        Some(Word {
+            size,
+            big_endian,
+        })
+    }
+
+
+    fn max(&self) -> u64 {
+        if self.size == 8 {
+            u64::MAX
+        }
+        else {
+            (1 << (8 * self.size)) - 1
+        }
+    }
+
+
+    fn read(&self, bytes:&[u8]) -> u64 {
+        let mut value = 0;
+        for i in 0..self.size {
+            let byte = if self.big_endian {bytes[i]} else {bytes[self.size - 1 - i]};
+            value = (value << 8) | u64::from(byte);
+        }
+        value
+    }
+
+
+    fn write(&self, value:u64, bytes:&mut [u8]) {
+        for i in 0..self.size {
+            let byte = ((value >> (8 * i)) & 0xff) as u8;
+            if self.big_endian {
+                bytes[self.size - 1 - i] = byte;
+            }
+            else {
+                bytes[i] = byte;
+            }
+        }
+    }
+}
+
+
+/// Something done in place to every byte or word in a range, e.g. `~xor 5a`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transform {
+    /// XOR with a key, which repeats across the range
+    Xor(Vec<u8>),
+    Add(u64, Word),
+    Sub(u64, Word),
+    Not,
+    RotateLeft(u32),
+    RotateRight(u32),
+    SwapNibbles,
+
+    /// Reverse the order of the bytes in each word of this many bytes, e.g.
+    /// to turn big-endian words little-endian
+    Swap(usize),
+
+    /// Reverse the order of all the bytes
+    Reverse,
+}
+
+
+impl Transform {
+    /// `args` are what follow `~`, e.g. `["add32be", "10"]`.  Numbers are in
+    /// `radix`.
+    pub fn from_args(args:&[String], radix:u32) -> Result<Transform, String> {
+        let name = match args.first() {
+            Some(name) => name.as_str(),
+            None => {
+                return Err("No transform given, e.g. ~xor 5a".to_owned());
+            },
+        };
+        let operand = args[1..].join("");
+
+        if name.starts_with("add") || name.starts_with("sub") {
+            let word = match Word::from_suffix(&name[3..]) {
+                Some(word) => word,
+                None => {
+                    return Err(format!("Don't understand '{}'.  Try e.g. \
+                            {}, {}16le or {}32be", name, &name[..3],
+                            &name[..3], &name[..3]));
+                },
+            };
+            let amount = match u64::from_str_radix(&operand, radix) {
+                Ok(amount) => amount,
+                Err(_) => {
+                    return Err(format!("Can't interpret '{}' as a number",
+                            operand));
+                },
+            };
+            if amount > word.max() {
+                return Err(format!("{} doesn't fit in {} byte(s)", operand,
+                        word.size));
+            }
+
+            return Ok(if name.starts_with("add") {
+                Transform::Add(amount, word)
+            }
+            else {
+                Transform::Sub(amount, word)
+            });
+        }
+
+        match name {
+            "xor" => {
+                let key = ec::bytes_from_string(&operand)?;
+                if key.is_empty() {
+                    return Err("No key given to xor with".to_owned());
+                }
+                Ok(Transform::Xor(key))
+            },
+            "not" => Ok(Transform::Not),
+            "rol" | "ror" => {
+                let bits = match u32::from_str_radix(&operand, radix) {
+                    Ok(bits) if bits < 8 => bits,
+                    _ => {
+                        return Err(format!("Can only rotate by 0 - 7 bits, \
+                                not '{}'", operand));
+                    },
+                };
+                if name == "rol" {
+                    Ok(Transform::RotateLeft(bits))
+                }
+                else {
+                    Ok(Transform::RotateRight(bits))
+                }
+            },
+            "nibbles" => Ok(Transform::SwapNibbles),
+            "swap2" => Ok(Transform::Swap(2)),
+            "swap4" => Ok(Transform::Swap(4)),
+            "swap8" => Ok(Transform::Swap(8)),
+            "reverse" => Ok(Transform::Reverse),
+            _ => Err(format!("Don't know the transform '{}'", name)),
+        }
+    }
+
+
+    /// What `bytes` become.  Fails if they aren't a whole number of words.
+    pub fn apply(&self, bytes:&[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Transform::Xor(key) => {
+                Ok(bytes.iter().zip(key.iter().cycle())
+                        .map(|(byte, key)| byte ^ key).collect())
+            },
+            Transform::Add(amount, word) | Transform::Sub(amount, word) => {
+                if bytes.len() % word.size != 0 {
+                    return Err(format!("{} byte(s) isn't a whole number of \
+                            {} byte words", bytes.len(), word.size));
+                }
+
+                let adding = matches!(self, Transform::Add(..));
+                let mut transformed = bytes.to_vec();
+                for chunk in transformed.chunks_mut(word.size) {
+                    let value = word.read(chunk);
+                    let value = if adding {
+                        value.wrapping_add(*amount)
+                    }
+                    else {
+                        value.wrapping_sub(*amount)
+                    };
+                    word.write(value & word.max(), chunk);
+                }
+                Ok(transformed)
+            },
+            Transform::Not => {
+                Ok(bytes.iter().map(|byte| !byte).collect())
+            },
+            Transform::RotateLeft(bits) => {
+                Ok(bytes.iter().map(|byte| byte.rotate_left(*bits)).collect())
+            },
+            Transform::RotateRight(bits) => {
+                Ok(bytes.iter().map(|byte| byte.rotate_right(*bits)).collect())
+            },
+            Transform::SwapNibbles => {
+                Ok(bytes.iter().map(|byte| byte.rotate_left(4)).collect())
+            },
+            Transform::Swap(size) => {
+                if bytes.len() % size != 0 {
+                    return Err(format!("{} byte(s) isn't a whole number of \
+                            {} byte words", bytes.len(), size));
+                }
+
+                let mut transformed = bytes.to_vec();
+                for chunk in transformed.chunks_mut(*size) {
+                    chunk.reverse();
+                }
+                Ok(transformed)
+            },
+            Transform::Reverse => {
+                Ok(bytes.iter().rev().cloned().collect())
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(args:&[&str]) -> Transform {
+        let args:Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Transform::from_args(&args, 16).unwrap()
+    }
+
+    #[test]
+    fn test_add_bytes() {
+        assert_eq!(transform(&["add", "1"]).apply(&[0x00, 0x7f, 0xff]),
+                Ok(vec![0x01, 0x80, 0x00]));
+        assert_eq!(transform(&["sub", "2"]).apply(&[0x01, 0x10]),
+                Ok(vec![0xff, 0x0e]));
+    }
+
+    #[test]
+    fn test_add_words() {
+        /* Carries into the next byte of the word, not the next word */
+        assert_eq!(transform(&["add16le", "1"]).apply(&[0xff, 0x00, 0xff, 0xff]),
+                Ok(vec![0x00, 0x01, 0x00, 0x00]));
+        assert_eq!(transform(&["add16be", "1"]).apply(&[0x00, 0xff, 0xff, 0xff]),
+                Ok(vec![0x01, 0x00, 0x00, 0x00]));
+        assert_eq!(transform(&["sub32le", "1"]).apply(&[0x00, 0x00, 0x00, 0x00]),
+                Ok(vec![0xff, 0xff, 0xff, 0xff]));
+        assert_eq!(transform(&["sub32be", "100"]).apply(&[0x00, 0x00, 0x01, 0x00]),
+                Ok(vec![0x00, 0x00, 0x00, 0x00]));
+        assert_eq!(transform(&["add64be", "ffffffffffffffff"])
+                .apply(&[0, 0, 0, 0, 0, 0, 0, 2]),
+                Ok(vec![0, 0, 0, 0, 0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_words_must_fit() {
+        assert!(transform(&["add32le", "1"]).apply(&[0x00, 0x00, 0x00]).is_err());
+        assert!(transform(&["swap4"]).apply(&[0x00, 0x00]).is_err());
+        let args = vec!["add16le".to_owned(), "10000".to_owned()];
+        assert!(Transform::from_args(&args, 16).is_err());
+    }
+
+    #[test]
+    fn test_other_transforms() {
+        assert_eq!(transform(&["xor", "ff00"]).apply(&[0x0f, 0x0f, 0x0f]),
+                Ok(vec![0xf0, 0x0f, 0xf0]));
+        assert_eq!(transform(&["rol", "1"]).apply(&[0x81]), Ok(vec![0x03]));
+        assert_eq!(transform(&["nibbles"]).apply(&[0x12]), Ok(vec![0x21]));
+        assert_eq!(transform(&["swap2"]).apply(&[1, 2, 3, 4]), Ok(vec![2, 1, 4, 3]));
+        assert_eq!(transform(&["reverse"]).apply(&[1, 2, 3]), Ok(vec![3, 2, 1]));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::preferences::Backup;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+
+/// Writes `bytes` to `path` without ever leaving a half-written file there.
+/// They're written to a temporary file in the same directory, synced to disk
+/// and then renamed over `path`, keeping its permissions and ownership.
+///
+/// If `path` already exists, what was there is first copied to a backup file
+/// according to `backup`.  A `file~` backup is only made the first time
+/// `file` is written, which `backed_up` keeps track of, so it holds what was
+/// there before any writing.  Timestamped backups are made on every write.
+pub fn write_atomically(path:&Path, bytes:&[u8], backup:Backup,
+        backed_up:&mut HashSet<PathBuf>) -> Result<(), String> {
+    /* Write through symlinks rather than replacing them */
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(_) => path.to_path_buf(),
+    };
+    let existing = fs::metadata(&path).ok();
+
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().into_owned(),
+        None => {
+            return Err(format!("{} isn't a file name", path.display()));
+        },
+    };
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let temp_path = dir.join(format!(".{}.edhex-{}", file_name,
+            std::process::id()));
+
+    if let Err(error) = write_and_sync(&temp_path, bytes) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(format!("Couldn't write to {}: {}", temp_path.display(),
+                error));
+    }
+
+    if let Some(metadata) = &existing {
+        if let Err(error) = copy_ownership(&temp_path, metadata) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(format!("Couldn't give {} the same owner and \
+                    permissions as {}: {}", temp_path.display(),
+                    path.display(), error));
+        }
+
+        let backup = match backup {
+            Backup::Tilde if backed_up.contains(&path) => Backup::Off,
+            _ => backup,
+        };
+        if let Some(backup_path) = backup_path(&path, backup) {
+            if let Err(error) = fs::copy(&path, &backup_path) {
+                let _ = fs::remove_file(&temp_path);
+                return Err(format!("Couldn't back up {} to {}: {}",
+                        path.display(), backup_path.display(), error));
+            }
+            backed_up.insert(path.clone());
+        }
+    }
+
+    if let Err(error) = fs::rename(&temp_path, &path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(format!("Couldn't move {} to {}: {}", temp_path.display(),
+                path.display(), error));
+    }
+
+    /* Make the rename itself survive a crash.  Not every platform can open a
+     * directory, so this is best effort. */
+    if let Ok(dir) = fs::File::open(&dir) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
+
+
+fn write_and_sync(path:&Path, bytes:&[u8]) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+
+#[cfg(unix)]
+fn copy_ownership(path:&Path, metadata:&fs::Metadata) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    /* Only root can give a file away, so failing to change the owner to
+     * someone else isn't worth stopping for */
+    let _ = std::os::unix::fs::chown(path, Some(metadata.uid()),
+            Some(metadata.gid()));
+    fs::set_permissions(path, metadata.permissions())
+}
+
+
+#[cfg(not(unix))]
+fn copy_ownership(path:&Path, metadata:&fs::Metadata) -> std::io::Result<()> {
+    fs::set_permissions(path, metadata.permissions())
+}
+
+
+/// `file~` or `file.1700000000~` for seconds since the epoch.  If there's
+/// already a backup from that second, `file.1700000000.1~` and so on.
+fn backup_path(path:&Path, backup:Backup) -> Option<PathBuf> {
+    let with_suffix = |suffix:String| {
+        let mut backup_path = path.as_os_str().to_owned();
+        backup_path.push(suffix);
+        PathBuf::from(backup_path)
+    };
+
+    match backup {
+        Backup::Off => None,
+        Backup::Tilde => Some(with_suffix("~".to_owned())),
+        Backup::Timestamped => {
+            let seconds = match SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(since) => since.as_secs(),
+                Err(_) => 0,
+            };
+            let mut backup_path = with_suffix(format!(".{}~", seconds));
+            let mut n = 0;
+            while backup_path.exists() {
+                n += 1;
+                backup_path = with_suffix(format!(".{}.{}~", seconds, n));
+            }
+            Some(backup_path)
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for each test
+    fn scratch_dir(name:&str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("edhex-test-{}-{}",
+                std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_backup_path() {
+        let dir = scratch_dir("backup_path");
+        let path = dir.join("file.bin");
+        assert_eq!(backup_path(&path, Backup::Off), None);
+        assert_eq!(backup_path(&path, Backup::Tilde),
+                Some(dir.join("file.bin~")));
+
+        let first = backup_path(&path, Backup::Timestamped).unwrap();
+        let name = first.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with("file.bin.") && name.ends_with('~'));
+
+        /* Never the name of a backup that's already there */
+        fs::write(&first, b"").unwrap();
+        let second = backup_path(&path, Backup::Timestamped).unwrap();
+        assert_ne!(first, second);
+        assert!(!second.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = scratch_dir("write_atomically");
+        let path = dir.join("file.bin");
+        let mut backed_up = HashSet::new();
+
+        /* Nothing to back up the first time */
+        write_atomically(&path, &[0xde, 0xad], Backup::Tilde, &mut backed_up)
+                .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![0xde, 0xad]);
+        assert!(!dir.join("file.bin~").exists());
+
+        write_atomically(&path, &[0xbe, 0xef], Backup::Tilde, &mut backed_up)
+                .unwrap();
+        write_atomically(&path, &[0x00], Backup::Tilde, &mut backed_up)
+                .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![0x00]);
+
+        /* The ~ backup is what was there before the first write over it */
+        assert_eq!(fs::read(dir.join("file.bin~")).unwrap(), vec![0xde, 0xad]);
+
+        /* No temporary files left behind */
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomically_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("keeps_permissions");
+        let path = dir.join("script.sh");
+        fs::write(&path, b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o751)).unwrap();
+
+        write_atomically(&path, b"#!/bin/sh\ntrue\n", Backup::Off,
+                &mut HashSet::new()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o751);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}